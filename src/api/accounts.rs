@@ -0,0 +1,304 @@
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{
+        SaltString,
+        rand_core::{OsRng, RngCore},
+    },
+};
+use axum::{
+    Json, Router,
+    extract::{FromRequestParts, OptionalFromRequestParts, Request, State},
+    http::{StatusCode, request::Parts},
+    middleware::Next,
+    response::Response,
+    routing::{get, post},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use rusqlite::{Connection, ErrorCode, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use time::Duration;
+use ts_rs::TS;
+
+use super::{
+    AppState,
+    error::{ApiError, ApiResult},
+    viewer::SHARED_USER,
+};
+
+const SESSION_COOKIE: &str = "viewer_session";
+const SESSION_DAYS: i64 = 30;
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+tokio::task_local! {
+    static CURRENT_USER: Option<u32>;
+}
+
+/// The user a request is made by, from its session cookie, or the shared
+/// user when accounts are off.
+///
+/// Rejects the request with 401 when nobody is logged in.
+#[derive(Debug, Clone, Copy)]
+pub struct User(pub u32);
+
+impl<S: Send + Sync> FromRequestParts<S> for User {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<User>()
+            .copied()
+            .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "Log in first"))
+    }
+}
+
+impl<S: Send + Sync> OptionalFromRequestParts<S> for User {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts.extensions.get::<User>().copied())
+    }
+}
+
+/// User of the request being handled, for queries filtering by
+/// `viewer_user()`. `None` outside a request or when logged out.
+pub fn current_user() -> Option<u32> {
+    CURRENT_USER.try_with(|user| *user).ok().flatten()
+}
+
+/// Work out who is making the request, see [`User`].
+pub async fn sessions(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    mut req: Request,
+    next: Next,
+) -> ApiResult<Response> {
+    let user = if !state.public.accounts {
+        Some(SHARED_USER)
+    } else if let Some(cookie) = jar.get(SESSION_COOKIE) {
+        let token = cookie.value().to_string();
+        state
+            .with_accounts(move |conn| Ok(session_user(conn, &token)?))
+            .await?
+    } else {
+        None
+    };
+
+    if let Some(user) = user {
+        req.extensions_mut().insert(User(user));
+    }
+    Ok(CURRENT_USER.scope(user, next.run(req)).await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Credentials {
+    name: String,
+    password: String,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct Account {
+    pub id: u32,
+    pub name: String,
+}
+
+pub fn wrap_accounts_route(router: Router<AppState>) -> Router<AppState> {
+    router.route("/users", post(register_handler)).route(
+        "/session",
+        get(session_handler)
+            .post(login_handler)
+            .delete(logout_handler),
+    )
+}
+
+async fn register_handler(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    Json(credentials): Json<Credentials>,
+) -> ApiResult<(StatusCode, CookieJar, Json<Account>)> {
+    let name = credentials.name.trim().to_string();
+    if name.is_empty() {
+        return Err(ApiError::bad_request("Name must not be empty"));
+    }
+    if credentials.password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(ApiError::bad_request(format!(
+            "Password must be at least {MIN_PASSWORD_LENGTH} characters"
+        )));
+    }
+
+    let hash = hash_password(credentials.password).await?;
+    let (account, token) = state
+        .with_accounts(move |conn| {
+            let inserted = conn.execute(
+                "INSERT INTO users (name, password) VALUES (?, ?)",
+                [&name, &hash],
+            );
+            match inserted {
+                Err(rusqlite::Error::SqliteFailure(err, _))
+                    if err.code == ErrorCode::ConstraintViolation =>
+                {
+                    return Err(ApiError::new(StatusCode::CONFLICT, "Name already taken"));
+                }
+                result => result?,
+            };
+
+            let id = conn.last_insert_rowid() as u32;
+            Ok((Account { id, name }, new_session(conn, id)?))
+        })
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        jar.add(session_cookie(token)),
+        Json(account),
+    ))
+}
+
+async fn login_handler(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    Json(credentials): Json<Credentials>,
+) -> ApiResult<(CookieJar, Json<Account>)> {
+    let name = credentials.name.trim().to_string();
+    let found = state
+        .with_accounts(move |conn| {
+            let found = conn
+                .query_row(
+                    "SELECT id, name, password FROM users WHERE name = ?",
+                    [name],
+                    |row| {
+                        Ok((
+                            Account {
+                                id: row.get(0)?,
+                                name: row.get(1)?,
+                            },
+                            row.get::<_, String>(2)?,
+                        ))
+                    },
+                )
+                .optional()?;
+            Ok(found)
+        })
+        .await?;
+
+    let verified = match found {
+        Some((account, hash)) => verify_password(credentials.password, hash)
+            .await?
+            .then_some(account),
+        None => None,
+    };
+    let Some(account) = verified else {
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "Wrong name or password",
+        ));
+    };
+
+    let id = account.id;
+    let token = state
+        .with_accounts(move |conn| Ok(new_session(conn, id)?))
+        .await?;
+
+    Ok((jar.add(session_cookie(token)), Json(account)))
+}
+
+async fn session_handler(
+    User(id): User,
+    State(state): State<AppState>,
+) -> ApiResult<Json<Account>> {
+    state
+        .with_accounts(move |conn| {
+            let name = conn
+                .query_row("SELECT name FROM users WHERE id = ?", [id], |row| {
+                    row.get(0)
+                })
+                .optional()?
+                // The shared user has no row
+                .unwrap_or_default();
+            Ok(Json(Account { id, name }))
+        })
+        .await
+}
+
+async fn logout_handler(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> ApiResult<(StatusCode, CookieJar)> {
+    if let Some(cookie) = jar.get(SESSION_COOKIE) {
+        let token = cookie.value().to_string();
+        state
+            .with_accounts(move |conn| {
+                conn.execute("DELETE FROM sessions WHERE token = ?", [token])?;
+                Ok(())
+            })
+            .await?;
+    }
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        jar.remove(Cookie::build(SESSION_COOKIE).path("/")),
+    ))
+}
+
+/// Hash `password` for storing, on the blocking thread pool as Argon2 is
+/// slow on purpose. The accounts database is not held meanwhile.
+async fn hash_password(password: String) -> ApiResult<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|_| ApiError::bad_request("Password cannot be hashed"))
+    })
+    .await?
+}
+
+/// Whether `password` matches the stored `hash`, checked like
+/// [`hash_password`] off the async runtime.
+async fn verify_password(password: String, hash: String) -> ApiResult<bool> {
+    let verified = tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await?;
+    Ok(verified)
+}
+
+/// Start a session for `user`, clearing out expired ones on the way.
+fn new_session(conn: &Connection, user: u32) -> Result<String, rusqlite::Error> {
+    conn.execute(
+        &format!("DELETE FROM sessions WHERE created <= datetime('now', '-{SESSION_DAYS} days')"),
+        [],
+    )?;
+
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = BASE64_URL_SAFE_NO_PAD.encode(bytes);
+
+    conn.execute(
+        "INSERT INTO sessions (token, user) VALUES (?, ?)",
+        (&token, user),
+    )?;
+    Ok(token)
+}
+
+fn session_user(conn: &Connection, token: &str) -> Result<Option<u32>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT user FROM sessions WHERE token = ? AND created > datetime('now', '-{SESSION_DAYS} days')"
+    ))?;
+    stmt.query_row([token], |row| row.get(0)).optional()
+}
+
+fn session_cookie(token: String) -> Cookie<'static> {
+    Cookie::build((SESSION_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::days(SESSION_DAYS))
+        .build()
+}
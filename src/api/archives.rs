@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use axum::{Json, extract::State};
+use serde::Serialize;
+use ts_rs::TS;
+
+use super::{
+    AppState,
+    error::ApiResult,
+    summary::{SummaryResponse, summary},
+};
+use crate::config::Archive;
+
+/// Every archive served along with its state, main one first.
+pub type Archives = Arc<Vec<(Archive, AppState)>>;
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ArchiveResponse {
+    pub name: String,
+    /// Prefix of the archive's `api`, `resource` and `images` routes
+    pub base: String,
+    pub summary: SummaryResponse,
+}
+
+/// Every archive served, main one first, so the frontend can switch.
+pub async fn get_archives_api(
+    State(archives): State<Archives>,
+) -> ApiResult<Json<Vec<ArchiveResponse>>> {
+    let mut list = Vec::with_capacity(archives.len());
+    for (archive, state) in archives.iter() {
+        list.push(ArchiveResponse {
+            name: archive.name.clone(),
+            base: archive.base.clone(),
+            summary: summary(state).await?,
+        });
+    }
+    Ok(Json(list))
+}
@@ -1,14 +1,14 @@
 use axum::{
     Json, Router,
     extract::{Path, State},
-    http::StatusCode,
     routing::get,
 };
 use post_archiver::{Alias, Author, AuthorId, PlatformId, query::Totalled, utils::AsTable};
 
 use crate::api::{
     AppState,
-    category::{get_category_handler, list_category_handler},
+    category::{download_category_handler, get_category_handler, list_category_handler},
+    error::{ApiError, ApiResult},
     relation::{RequireRelations, WithRelations},
 };
 
@@ -22,6 +22,8 @@ impl RequireRelations for Author {
 
 impl Category for Author {
     type Id = AuthorId;
+    const POSTS_FILTER: &'static str =
+        "EXISTS (SELECT 1 FROM author_posts WHERE post = posts.id AND author = ?)";
     const DEFAULT_ORDER_BY: CategoryOrderBy = CategoryOrderBy::Updated;
 
     fn wrap_category_route(router: Router<AppState>) -> Router<AppState> {
@@ -34,6 +36,10 @@ impl Category for Author {
                 &format!("/{}/{{id}}", Self::TABLE_NAME),
                 get(get_category_handler::<Self>),
             )
+            .route(
+                &format!("/{}/{{id}}/download", Self::TABLE_NAME),
+                get(download_category_handler::<Self>),
+            )
             .route(
                 &format!("/{}/{{id}}/aliases", Self::TABLE_NAME),
                 get(author_aliases_handler),
@@ -44,16 +50,17 @@ impl Category for Author {
 pub async fn author_aliases_handler(
     State(state): State<AppState>,
     Path(id): Path<AuthorId>,
-) -> Result<Json<WithRelations<Totalled<Vec<Alias>>>>, StatusCode> {
-    let manager = &state.manager();
-    let items = manager
-        .list_author_aliases(id)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let total = items.len() as u64;
-
-    WithRelations::new(manager, Totalled { items, total })
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
-        .map(Json::from)
+) -> ApiResult<Json<WithRelations<Totalled<Vec<Alias>>>>> {
+    state
+        .with_manager(move |manager| {
+            let items = manager.list_author_aliases(id)?;
+            let total = items.len() as u64;
+
+            WithRelations::new(manager, Totalled { items, total })
+                .map_err(ApiError::from)
+                .map(Json::from)
+        })
+        .await
 }
 
 impl RequireRelations for Alias {
@@ -12,4 +12,6 @@ impl RequireRelations for Collection {
 
 impl Category for Collection {
     type Id = CollectionId;
+    const POSTS_FILTER: &'static str =
+        "EXISTS (SELECT 1 FROM collection_posts WHERE post = posts.id AND collection = ?)";
 }
@@ -3,25 +3,38 @@ pub mod collection;
 pub mod platform;
 pub mod tag;
 
-use std::{fmt::Debug, hash::Hash};
+use std::{fmt::Debug, hash::Hash, rc::Rc};
 
 use axum::{
     Json, Router,
     extract::{Path, State},
-    http::StatusCode,
+    response::Response,
     routing::get,
 };
 use axum_extra::extract::Query;
 use cached::Cached;
-use post_archiver::{manager::PostArchiverManager, query::Totalled, utils::AsTable};
+use post_archiver::{
+    PostId,
+    manager::PostArchiverManager,
+    query::{Param, Query as _, Totalled},
+    utils::AsTable,
+};
 use rusqlite::{OptionalExtension, ToSql};
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
 use super::{
-    AppState,
+    AppState, Caches,
+    download::{DownloadQuery, check_local, posts_entries, zip_response},
+    error::{ApiError, ApiResult},
+    posts::SearchQuery,
     relation::{RequireRelations, WithRelations},
-    utils::Pagination,
+    utils::{
+        Pagination,
+        cursor::{Cursor, CursorValue},
+        post_preview::PostPreview,
+        with_cursor::WithCursor,
+    },
 };
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -33,8 +46,12 @@ pub enum CategoryOrderBy {
     Random,
 }
 
-pub trait Category: RequireRelations + Serialize + AsTable + Debug + TS + Sized + 'static {
+pub trait Category:
+    RequireRelations + Serialize + AsTable + Debug + TS + Send + Sized + 'static
+{
     type Id: From<u32> + Debug + Serialize + ToSql + Copy + Eq + Hash + Sync + Send + 'static;
+    /// `WHERE` clause on `posts` keeping the posts in the category bound to `?`.
+    const POSTS_FILTER: &'static str;
     const DEFAULT_ORDER_BY: CategoryOrderBy = CategoryOrderBy::Name;
 
     fn wrap_category_route(router: Router<AppState>) -> Router<AppState> {
@@ -47,46 +64,108 @@ pub trait Category: RequireRelations + Serialize + AsTable + Debug + TS + Sized
                 &format!("/{}/{{id}}", Self::TABLE_NAME),
                 get(get_category_handler::<Self>),
             )
+            .route(
+                &format!("/{}/{{id}}/download", Self::TABLE_NAME),
+                get(download_category_handler::<Self>),
+            )
+    }
+
+    /// Sort column and direction for `order_by`, `None` for random order.
+    fn order(order_by: Option<CategoryOrderBy>) -> Option<(&'static str, &'static str)> {
+        match (order_by.unwrap_or(Self::DEFAULT_ORDER_BY), Self::TABLE_NAME) {
+            (CategoryOrderBy::Id, _) => Some(("id", "DESC")),
+            (CategoryOrderBy::Name, _) => Some(("name", "ASC")),
+            (CategoryOrderBy::Updated, "authors" | "posts") => Some(("updated", "DESC")),
+            (CategoryOrderBy::Updated, _) => Some(("id", "DESC")), // Fallback to id
+            (CategoryOrderBy::Random, _) => None,
+        }
+    }
+
+    /// Identifies the order a cursor was issued for, `None` for random order.
+    fn cursor_order(order_by: Option<CategoryOrderBy>) -> Option<String> {
+        Self::order(order_by).map(|(column, dir)| format!("{column} {dir}"))
     }
 
+    /// List a page of categories, along with the cursor of the next page.
     fn list(
         manager: &PostArchiverManager,
         pagination: Pagination,
         search: String,
         order_by: Option<CategoryOrderBy>,
-    ) -> Result<Vec<Self>, rusqlite::Error> {
+        cursor: Option<Cursor>,
+    ) -> Result<(Vec<Self>, Option<Cursor>), rusqlite::Error> {
         let params = pagination.params();
-        let (filter, search_params) = if search.is_empty() {
-            ("", None)
+        let order = Self::order(order_by);
+
+        let mut filters = vec![];
+        if !search.is_empty() {
+            filters.push("name LIKE concat('%',:search,'%')".to_string());
+        }
+        if let (Some((column, dir)), Some(_)) = (order, &cursor) {
+            filters.push(Cursor::seek_clause(column, "id", dir));
+        }
+        let filter = if filters.is_empty() {
+            String::new()
         } else {
-            ("WHERE name LIKE concat('%',:search,'%')", Some(search))
+            format!("WHERE {}", filters.join(" AND "))
+        };
+
+        let (column, order_sql) = match order {
+            Some(("id", dir)) => ("id", format!("ORDER BY id {dir}")),
+            // Break ties so pages don't overlap
+            Some((column, dir)) => (column, format!("ORDER BY {column} {dir}, id {dir}")),
+            None => ("NULL", "ORDER BY RANDOM()".to_string()),
         };
 
         let mut stmt = manager.conn().prepare_cached(&format!(
-            "SELECT * FROM {} {} {} LIMIT :limit OFFSET :offset",
+            "SELECT *, {} AS cursor_value FROM {} {} {} LIMIT :limit OFFSET :offset",
+            column,
             Self::TABLE_NAME,
             filter,
-            match (order_by.unwrap_or(Self::DEFAULT_ORDER_BY), Self::TABLE_NAME) {
-                (CategoryOrderBy::Id, _) => "ORDER BY id DESC",
-                (CategoryOrderBy::Name, _) => "ORDER BY name ASC",
-                (CategoryOrderBy::Updated, "authors" | "posts") => "ORDER BY updated DESC",
-                (CategoryOrderBy::Updated, _) => "ORDER BY id DESC", // Fallback to id
-                (CategoryOrderBy::Random, _) => "ORDER BY RANDOM()",
-            }
+            order_sql,
         ))?;
 
         let params = params
             .iter()
             .map(|(k, v)| (*k, v as &dyn ToSql))
-            .chain(search_params.as_ref().map(|s| (":search", s as &dyn ToSql)))
+            .chain((!search.is_empty()).then_some((":search", &search as &dyn ToSql)))
+            .chain(cursor.iter().flat_map(|cursor| {
+                [
+                    (":cursor_value", &cursor.value as &dyn ToSql),
+                    (":cursor_id", &cursor.id as &dyn ToSql),
+                ]
+            }))
             .collect::<Vec<(&'static str, &dyn ToSql)>>();
-        let list = stmt.query_map(params.as_slice(), Self::from_row)?;
+        let list = stmt.query_map(params.as_slice(), |row| {
+            Ok((
+                Self::from_row(row)?,
+                row.get::<_, Option<CursorValue>>("cursor_value")?,
+                row.get::<_, u32>("id")?,
+            ))
+        })?;
+        let list = list.collect::<Result<Vec<_>, _>>()?;
 
-        list.collect()
+        let next_cursor = match (Self::cursor_order(order_by), list.last()) {
+            (Some(order), Some((_, Some(value), id)))
+                if list.len() as u64 == pagination.limit() =>
+            {
+                Some(Cursor {
+                    order,
+                    value: value.clone(),
+                    id: *id,
+                })
+            }
+            _ => None,
+        };
+
+        Ok((
+            list.into_iter().map(|(item, _, _)| item).collect(),
+            next_cursor,
+        ))
     }
 
     fn total(
-        state: &AppState,
+        caches: &Caches,
         manager: &PostArchiverManager,
         search: String,
     ) -> Result<u64, rusqlite::Error> {
@@ -98,7 +177,7 @@ pub trait Category: RequireRelations + Serialize + AsTable + Debug + TS + Sized
             return stmt.query_row([search], |row| row.get(0));
         }
 
-        let mut cache = state.caches.tables.lock().unwrap();
+        let mut cache = caches.tables.lock().unwrap();
 
         if let Some(cached) = cache.cache_get(&Self::TABLE_NAME) {
             return Ok(*cached);
@@ -140,27 +219,82 @@ async fn list_category_handler<T: Category>(
     Query(filter): Query<Filter>,
     Query(pagination): Query<Pagination>,
     State(state): State<AppState>,
-) -> Result<Json<WithRelations<Totalled<Vec<T>>>>, StatusCode> {
-    let manager = &state.manager();
-    let items = T::list(manager, pagination, filter.search.clone(), filter.order_by)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let total =
-        T::total(&state, manager, filter.search).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    WithRelations::new(manager, Totalled { items, total })
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
-        .map(Json::from)
+) -> ApiResult<Json<WithRelations<WithCursor<Totalled<Vec<T>>>>>> {
+    let cursor = pagination.cursor(T::cursor_order(filter.order_by).as_deref())?;
+    let caches = state.caches.clone();
+
+    state
+        .with_manager(move |manager| {
+            let (items, next_cursor) = T::list(
+                manager,
+                pagination,
+                filter.search.clone(),
+                filter.order_by,
+                cursor,
+            )?;
+            let total = T::total(&caches, manager, filter.search)?;
+
+            let next_cursor = next_cursor.map(|cursor| cursor.encode());
+            WithRelations::new(
+                manager,
+                WithCursor {
+                    inner: Totalled { items, total },
+                    next_cursor,
+                },
+            )
+            .map_err(ApiError::from)
+            .map(Json::from)
+        })
+        .await
 }
 
 async fn get_category_handler<T: Category>(
     Path(id): Path<u32>,
     State(state): State<AppState>,
-) -> Result<Json<WithRelations<T>>, StatusCode> {
-    let manager = &state.manager();
+) -> ApiResult<Json<WithRelations<T>>> {
     let id: T::Id = id.into();
 
-    T::get(manager, id)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-        .ok_or(StatusCode::NOT_FOUND)
-        .map(Json::from)
+    state
+        .with_manager(move |manager| {
+            T::get(manager, id)?
+                .ok_or_else(|| ApiError::not_found(format!("Not found in {}", T::TABLE_NAME)))
+                .map(Json::from)
+        })
+        .await
+}
+
+/// Every post in the category, optionally narrowed down by the same filters
+/// as `/posts`, as a ZIP with one directory per post.
+async fn download_category_handler<T: Category>(
+    Path(id): Path<u32>,
+    Query(searchs): Query<SearchQuery>,
+    Query(options): Query<DownloadQuery>,
+    State(state): State<AppState>,
+) -> ApiResult<Response> {
+    if searchs.is_full_text() {
+        state.sync_search().await?;
+    }
+    let public = state.public.clone();
+
+    let posts = state
+        .with_manager(move |manager| {
+            if T::get(manager, id.into())?.is_none() {
+                return Err(ApiError::not_found(format!(
+                    "Not found in {}",
+                    T::TABLE_NAME
+                )));
+            }
+
+            let mut query = searchs.build(manager, None)?;
+            query.filter(T::POSTS_FILTER, [Rc::new(id) as Param]);
+            let posts: Vec<PostPreview> = query.query()?;
+            let posts: Vec<PostId> = posts.into_iter().map(|post| post.id).collect();
+
+            check_local(&public, manager, &posts)?;
+            Ok(posts)
+        })
+        .await?;
+
+    let entries = posts_entries(state.pool.clone(), posts, options);
+    Ok(zip_response(&format!("{}-{id}", T::TABLE_NAME), entries))
 }
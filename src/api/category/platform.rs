@@ -8,4 +8,5 @@ impl RequireRelations for Platform {}
 
 impl Category for Platform {
     type Id = PlatformId;
+    const POSTS_FILTER: &'static str = "posts.platform = ?";
 }
@@ -12,4 +12,6 @@ impl RequireRelations for Tag {
 
 impl Category for Tag {
     type Id = TagId;
+    const POSTS_FILTER: &'static str =
+        "EXISTS (SELECT 1 FROM post_tags WHERE post = posts.id AND tag = ?)";
 }
@@ -0,0 +1,460 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    fs::File,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::Query;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use post_archiver::{Content, FileMeta, FileMetaId, Post, PostId, manager::PostArchiverManager};
+use r2d2::Pool;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, warn};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::config::PublicConfig;
+
+use super::{
+    AppState,
+    error::{ApiError, ApiResult},
+    files::{PATH_SEGMENT, list_post_files},
+    pool::ArchiveConnections,
+};
+
+/// Bytes buffered before a chunk is handed to the response body.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks buffered before the zip writer waits for the client to catch up.
+const CHANNEL_CAPACITY: usize = 8;
+/// Characters of the title kept in a post's directory name.
+const TITLE_CHARS: usize = 80;
+/// Added to a ZIP listing the files that could not be read, as the response
+/// has already started by then.
+const MISSING_FILES_NAME: &str = "MISSING.txt";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DownloadQuery {
+    /// Add the post itself as `post.json`
+    #[serde(default)]
+    pub json: bool,
+    /// Add the text content rendered as `post.md`
+    #[serde(default)]
+    pub markdown: bool,
+}
+
+/// One file in a ZIP download.
+#[derive(Debug)]
+pub struct ZipEntry {
+    pub name: String,
+    pub source: ZipSource,
+    pub modified: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub enum ZipSource {
+    /// A file on disk, read while streaming
+    File(PathBuf),
+    Data(Vec<u8>),
+}
+
+/// All files of post `id` as a ZIP archive.
+pub async fn download_post_handler(
+    Path(id): Path<PostId>,
+    Query(query): Query<DownloadQuery>,
+    State(state): State<AppState>,
+) -> ApiResult<Response> {
+    let public = state.public.clone();
+
+    let (name, entries) = state
+        .with_manager(move |manager| {
+            let Some(post) = manager.get_post(id)? else {
+                return Err(ApiError::not_found("Post not found"));
+            };
+
+            check_local(&public, manager, &[post.id])?;
+            let entries = post_entries(manager, &post, "", &query)?;
+
+            Ok((format!("{}-{}", post.id, post.title), entries))
+        })
+        .await?;
+
+    Ok(zip_response(&name, [Ok(entries)]))
+}
+
+/// Entries for the files of `post`, placed under `dir`.
+pub fn post_entries(
+    manager: &PostArchiverManager,
+    post: &Post,
+    dir: &str,
+    query: &DownloadQuery,
+) -> Result<Vec<ZipEntry>, rusqlite::Error> {
+    let files = list_post_files(manager.conn(), post.id)?.unwrap_or_default();
+
+    // Filenames are unique within a post, but the thumbnail may come from
+    // another post, and none may take the name of an entry written alongside
+    let mut used: HashSet<String> = HashSet::from([
+        "post.json".into(),
+        "post.md".into(),
+        MISSING_FILES_NAME.into(),
+    ]);
+    let names: HashMap<_, _> = files
+        .iter()
+        .map(|file| {
+            let mut name = sanitize_filename(&file.filename);
+            if !used.insert(name.clone()) {
+                name = format!("{}-{name}", file.id);
+                used.insert(name.clone());
+            }
+            (file.id, name)
+        })
+        .collect();
+
+    let mut entries = vec![];
+    let entry = |name: &str, source| ZipEntry {
+        name: format!("{dir}{name}"),
+        source,
+        modified: post.updated,
+    };
+
+    if query.json {
+        let json = serde_json::to_vec_pretty(post).unwrap();
+        entries.push(entry("post.json", ZipSource::Data(json)));
+    }
+    if query.markdown {
+        let markdown = render_markdown(post, &files, &names);
+        entries.push(entry("post.md", ZipSource::Data(markdown.into_bytes())));
+    }
+    for file in &files {
+        let source = ZipSource::File(manager.path.join(file.path()));
+        entries.push(entry(&names[&file.id], source));
+    }
+
+    Ok(entries)
+}
+
+/// Entries for each of `posts` in its own directory, read from the archive one
+/// post at a time as the ZIP is written, so only the post being written is
+/// held in memory.
+///
+/// Posts deleted in the meantime are left out.
+pub fn posts_entries(
+    pool: Pool<ArchiveConnections>,
+    posts: Vec<PostId>,
+    query: DownloadQuery,
+) -> impl Iterator<Item = io::Result<Vec<ZipEntry>>> + Send + 'static {
+    posts.into_iter().map(move |id| {
+        // Taken per post, so a slow client does not hold on to a connection
+        let manager = pool.get().map_err(io::Error::other)?;
+        let Some(post) = manager.get_post(id).map_err(io::Error::other)? else {
+            return Ok(vec![]);
+        };
+        post_entries(&manager, &post, &post_directory(&post), &query).map_err(io::Error::other)
+    })
+}
+
+/// Directory of `post` in a multi-post archive, `<post-id>-<title>/`.
+pub fn post_directory(post: &Post) -> String {
+    let title: String = post.title.chars().take(TITLE_CHARS).collect();
+    format!("{}/", sanitize_filename(&format!("{}-{title}", post.id)))
+}
+
+/// Files can only be read from disk when the archive root is local; with a
+/// `resource_url` some of those of `posts` may live elsewhere.
+pub fn check_local(
+    public: &PublicConfig,
+    manager: &PostArchiverManager,
+    posts: &[PostId],
+) -> ApiResult<()> {
+    if public.resource_url.is_none() {
+        return Ok(());
+    }
+
+    for &post in posts {
+        let files = list_post_files(manager.conn(), post)?.unwrap_or_default();
+        if files
+            .iter()
+            .any(|file| !manager.path.join(file.path()).is_file())
+        {
+            return Err(ApiError::forbidden(
+                "Some files are not stored locally, download them from the resource URL",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn render_markdown(post: &Post, files: &[FileMeta], names: &HashMap<FileMetaId, String>) -> String {
+    let mut markdown = format!("# {}\n\n", post.title);
+    if let Some(source) = &post.source {
+        writeln!(markdown, "Source: <{source}>  ").unwrap();
+    }
+    writeln!(markdown, "Published: {}\n", post.published.to_rfc3339()).unwrap();
+
+    for content in &post.content {
+        match content {
+            Content::Text(text) => markdown.push_str(text),
+            Content::File(id) => {
+                let Some(file) = files.iter().find(|file| file.id == *id) else {
+                    continue;
+                };
+                let name = &names[&file.id];
+                let link = utf8_percent_encode(name, PATH_SEGMENT);
+                let bang = if file.mime.starts_with("image/") {
+                    "!"
+                } else {
+                    ""
+                };
+                write!(markdown, "{bang}[{name}]({link})").unwrap();
+            }
+        }
+        markdown.push_str("\n\n");
+    }
+
+    markdown
+}
+
+/// Make `name` safe to use as a single path segment inside an archive.
+pub fn sanitize_filename(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    match name.trim() {
+        "" | "." | ".." => "_".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Stream `entries` as a ZIP named `name`.zip.
+///
+/// The archive is written on a blocking thread into a bounded channel, so at
+/// most a few chunks are held in memory no matter how large the files are.
+/// `entries` come in batches, each only produced once the previous one is
+/// written; an error ends the download.
+pub fn zip_response<I>(name: &str, entries: I) -> Response
+where
+    I: IntoIterator<Item = io::Result<Vec<ZipEntry>>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChannelWriter {
+            tx: tx.clone(),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        };
+        if let Err(err) = write_zip(&mut writer, entries) {
+            // Fail the body so the client does not mistake a truncated
+            // archive for a complete one
+            if err.kind() != io::ErrorKind::BrokenPipe {
+                error!("Failed to write ZIP download: {err}");
+                let _ = tx.blocking_send(Err(err));
+            }
+        }
+    });
+
+    let filename = format!("{}.zip", sanitize_filename(name));
+    let ascii: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let disposition = format!(
+        "attachment; filename=\"{ascii}\"; filename*=UTF-8''{}",
+        utf8_percent_encode(&filename, NON_ALPHANUMERIC)
+    );
+
+    (
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+fn write_zip<W: Write>(
+    writer: W,
+    entries: impl IntoIterator<Item = io::Result<Vec<ZipEntry>>>,
+) -> io::Result<()> {
+    let mut zip = ZipWriter::new_stream(writer);
+    let mut missing = String::new();
+
+    for batch in entries {
+        for entry in batch? {
+            let options = SimpleFileOptions::default()
+                .compression_method(CompressionMethod::Stored)
+                .last_modified_time(zip_time(entry.modified));
+
+            match entry.source {
+                ZipSource::Data(data) => {
+                    zip.start_file(entry.name, options)?;
+                    zip.write_all(&data)?;
+                }
+                ZipSource::File(path) => {
+                    let mut file = match File::open(&path) {
+                        Ok(file) => file,
+                        Err(err) => {
+                            warn!("Skipping {} in ZIP download: {err}", path.display());
+                            let _ = writeln!(missing, "{}: {err}", entry.name);
+                            continue;
+                        }
+                    };
+                    let size = file.metadata()?.len();
+                    zip.start_file(entry.name, options.large_file(size >= u32::MAX as u64))?;
+                    io::copy(&mut file, &mut zip)?;
+                }
+            }
+        }
+    }
+
+    if !missing.is_empty() {
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file(MISSING_FILES_NAME, options)?;
+        zip.write_all(b"These files could not be read and are not included:\n\n")?;
+        zip.write_all(missing.as_bytes())?;
+    }
+
+    zip.finish()?.flush()
+}
+
+/// ZIP timestamps cannot go before 1980, fall back to the format's epoch.
+fn zip_time(time: DateTime<Utc>) -> zip::DateTime {
+    zip::DateTime::from_date_and_time(
+        time.year().clamp(1980, 2107) as u16,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .unwrap_or_default()
+}
+
+/// Sends written bytes to the response body in [`CHUNK_SIZE`] chunks.
+struct ChannelWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        self.tx
+            .blocking_send(Ok(Bytes::from(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use zip::ZipArchive;
+
+    use super::*;
+
+    #[test]
+    fn sanitize_filename_replaces_separators_and_reserved() {
+        assert_eq!(
+            sanitize_filename("a/b\\c:d*e?f\"g<h>i|j"),
+            "a_b_c_d_e_f_g_h_i_j"
+        );
+        assert_eq!(sanitize_filename("line\nbreak\0"), "line_break_");
+    }
+
+    #[test]
+    fn sanitize_filename_never_escapes_the_directory() {
+        for name in ["", "  ", ".", "..", " .. "] {
+            assert_eq!(sanitize_filename(name), "_", "{name:?}");
+        }
+        assert_eq!(sanitize_filename("../etc/passwd"), ".._etc_passwd");
+    }
+
+    #[test]
+    fn sanitize_filename_keeps_unicode_and_trims() {
+        assert_eq!(sanitize_filename("  龍の絵.png "), "龍の絵.png");
+    }
+
+    #[test]
+    fn write_zip_lists_missing_files() {
+        let entries = vec![
+            ZipEntry {
+                name: "post/post.json".to_string(),
+                source: ZipSource::Data(b"{}".to_vec()),
+                modified: Utc::now(),
+            },
+            ZipEntry {
+                name: "post/gone.png".to_string(),
+                source: ZipSource::File("/nonexistent/gone.png".into()),
+                modified: Utc::now(),
+            },
+        ];
+        let mut bytes = vec![];
+        write_zip(&mut bytes, [Ok(entries)]).unwrap();
+
+        let mut zip = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let names: Vec<String> = zip
+            .file_names()
+            .map(|name| name.unwrap().into_owned())
+            .collect();
+        assert!(names.contains(&"post/post.json".to_string()));
+        assert!(!names.contains(&"post/gone.png".to_string()));
+
+        let mut missing = String::new();
+        zip.by_name(MISSING_FILES_NAME)
+            .unwrap()
+            .read_to_string(&mut missing)
+            .unwrap();
+        assert!(missing.contains("post/gone.png"));
+    }
+
+    #[test]
+    fn write_zip_without_failures_has_no_list() {
+        let entries = vec![ZipEntry {
+            name: "post.json".to_string(),
+            source: ZipSource::Data(b"{}".to_vec()),
+            modified: Utc::now(),
+        }];
+        let mut bytes = vec![];
+        write_zip(&mut bytes, [Ok(entries)]).unwrap();
+
+        let zip = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let names: Vec<String> = zip
+            .file_names()
+            .map(|name| name.unwrap().into_owned())
+            .collect();
+        assert_eq!(names, ["post.json"]);
+    }
+}
@@ -0,0 +1,174 @@
+use std::{borrow::Cow, error::Error, sync::Arc};
+
+use axum::{
+    Json,
+    body::to_bytes,
+    extract::{OriginalUri, Request},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tracing::{debug, error};
+use ts_rs::TS;
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+/// Longest extractor rejection message read back by [`json_rejections`].
+const MAX_REJECTION_LEN: usize = 64 * 1024;
+
+/// A failed API request: the status and message sent to the client, and the
+/// underlying error, which is only logged.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: Cow<'static, str>,
+    source: Option<Arc<dyn Error + Send + Sync>>,
+}
+
+/// Body of every API error response.
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ErrorResponse {
+    /// HTTP status code
+    pub code: u16,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn bad_request(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn forbidden(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, message)
+    }
+
+    pub fn not_found(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    /// Attach the error that caused this one, to be logged.
+    pub fn with_source(mut self, source: impl Error + Send + Sync + 'static) -> Self {
+        self.source = Some(Arc::new(source));
+        self
+    }
+}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to query the archive",
+        )
+        .with_source(err)
+    }
+}
+
+impl From<post_archiver::error::Error> for ApiError {
+    fn from(err: post_archiver::error::Error) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to query the archive",
+        )
+        .with_source(err)
+    }
+}
+
+impl From<r2d2::Error> for ApiError {
+    fn from(err: r2d2::Error) -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The archive is busy, try again later",
+        )
+        .with_source(err)
+    }
+}
+
+impl From<tokio::task::JoinError> for ApiError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "Request handler failed").with_source(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse {
+            code: self.status.as_u16(),
+            message: self.message.to_string(),
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        // Picked up by `log_errors`, which knows the request path
+        response.extensions_mut().insert(LoggedError {
+            message: self.message,
+            source: self.source,
+        });
+        response
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LoggedError {
+    message: Cow<'static, str>,
+    source: Option<Arc<dyn Error + Send + Sync>>,
+}
+
+/// Log API errors along with the request they failed.
+pub async fn log_errors(request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    // Nested routers only see the rest of the path
+    let uri = match request.extensions().get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri.clone(),
+        None => request.uri().clone(),
+    };
+
+    let response = next.run(request).await;
+    let Some(logged) = response.extensions().get::<LoggedError>() else {
+        return response;
+    };
+
+    let status = response.status();
+    let message = match &logged.source {
+        Some(source) => format!("{}: {source}", logged.message),
+        None => logged.message.to_string(),
+    };
+    if status.is_server_error() {
+        error!("{method} {uri} failed with {status}: {message}");
+    } else {
+        debug!("{method} {uri} failed with {status}: {message}");
+    }
+
+    response
+}
+
+/// Give extractor rejections, which axum answers in plain text, the same JSON
+/// body as every other API error.
+pub async fn json_rejections(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+
+    let status = response.status();
+    let plain = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"text/plain"));
+    if !(status.is_client_error() || status.is_server_error())
+        || !plain
+        || response.extensions().get::<LoggedError>().is_some()
+    {
+        return response;
+    }
+
+    let message = match to_bytes(response.into_body(), MAX_REJECTION_LEN).await {
+        Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+        Err(_) => status.canonical_reason().unwrap_or_default().to_string(),
+    };
+    ApiError::new(status, message).into_response()
+}
@@ -0,0 +1,174 @@
+use std::{collections::HashMap, fmt::Debug};
+
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, put},
+};
+use axum_extra::extract::Query;
+use post_archiver::{Author, Collection, query::Totalled};
+use rusqlite::named_params;
+
+use super::{
+    AppState,
+    accounts::User,
+    error::{ApiError, ApiResult},
+    relation::{RelationTarget, RequireRelations, WithRelations},
+    utils::{Pagination, post_preview::PostPreview},
+    viewer::VIEWER_SCHEMA,
+};
+
+/// Something that can be starred, kept in a `favorite_*` table of the viewer
+/// database.
+pub trait Favorite:
+    RelationTarget<Id: From<u32>> + RequireRelations + Debug + Send + 'static
+{
+    const FAVORITES_TABLE: &'static str;
+
+    fn id(&self) -> u32;
+}
+
+impl Favorite for PostPreview {
+    const FAVORITES_TABLE: &'static str = "favorite_posts";
+
+    fn id(&self) -> u32 {
+        self.id.raw()
+    }
+}
+
+impl Favorite for Author {
+    const FAVORITES_TABLE: &'static str = "favorite_authors";
+
+    fn id(&self) -> u32 {
+        self.id.raw()
+    }
+}
+
+impl Favorite for Collection {
+    const FAVORITES_TABLE: &'static str = "favorite_collections";
+
+    fn id(&self) -> u32 {
+        self.id.raw()
+    }
+}
+
+pub fn wrap_favorites_route(router: Router<AppState>) -> Router<AppState> {
+    let router = wrap_favorite_route::<PostPreview>(router);
+    let router = wrap_favorite_route::<Author>(router);
+    wrap_favorite_route::<Collection>(router)
+}
+
+fn wrap_favorite_route<T: Favorite>(router: Router<AppState>) -> Router<AppState> {
+    router
+        .route(
+            &format!("/{}/{{id}}/favorite", T::TABLE_NAME),
+            put(star_handler::<T>).delete(unstar_handler::<T>),
+        )
+        .route(
+            &format!("/favorites/{}", T::TABLE_NAME),
+            get(list_favorites_handler::<T>),
+        )
+}
+
+async fn star_handler<T: Favorite>(
+    Path(id): Path<u32>,
+    User(user): User,
+    State(state): State<AppState>,
+) -> ApiResult<StatusCode> {
+    let exists = state
+        .with_manager(move |manager| Ok(!T::query(manager.conn(), [id.into()])?.is_empty()))
+        .await?;
+    if !exists {
+        return Err(ApiError::not_found(format!(
+            "Not found in {}",
+            T::TABLE_NAME
+        )));
+    }
+
+    state
+        .with_viewer(move |conn| {
+            conn.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO {} (user, id) VALUES (?, ?)",
+                    T::FAVORITES_TABLE
+                ),
+                [user, id],
+            )?;
+            Ok(StatusCode::NO_CONTENT)
+        })
+        .await
+}
+
+async fn unstar_handler<T: Favorite>(
+    Path(id): Path<u32>,
+    User(user): User,
+    State(state): State<AppState>,
+) -> ApiResult<StatusCode> {
+    state
+        .with_viewer(move |conn| {
+            conn.execute(
+                &format!(
+                    "DELETE FROM {} WHERE user = ? AND id = ?",
+                    T::FAVORITES_TABLE
+                ),
+                [user, id],
+            )?;
+            Ok(StatusCode::NO_CONTENT)
+        })
+        .await
+}
+
+/// The user's starred items still in the archive, most recently starred
+/// first.
+async fn list_favorites_handler<T: Favorite>(
+    Query(pagination): Query<Pagination>,
+    State(state): State<AppState>,
+) -> ApiResult<Json<WithRelations<Totalled<Vec<T>>>>> {
+    state
+        .with_manager(move |manager| {
+            let conn = manager.conn();
+            let favorites = format!(
+                "{VIEWER_SCHEMA}.{} WHERE user = viewer_user() AND id IN (SELECT id FROM {})",
+                T::FAVORITES_TABLE,
+                T::TABLE_NAME
+            );
+
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT id FROM {favorites} ORDER BY starred DESC, id DESC LIMIT :limit OFFSET :offset"
+            ))?;
+            let [(_, limit), (_, offset)] = pagination.params();
+            let ids = stmt
+                .query_map(
+                    named_params! { ":limit": limit, ":offset": offset },
+                    |row| row.get::<_, u32>(0),
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            let total = conn.query_row(&format!("SELECT COUNT() FROM {favorites}"), [], |row| {
+                row.get(0)
+            })?;
+
+            let mut items = T::query(conn, ids.iter().map(|&id| id.into()))?;
+            let rank: HashMap<u32, usize> =
+                ids.into_iter().enumerate().map(|(i, id)| (id, i)).collect();
+            items.sort_by_key(|item| rank[&item.id()]);
+
+            WithRelations::new(manager, Totalled { items, total })
+                .map_err(ApiError::from)
+                .map(Json::from)
+        })
+        .await
+}
+
+/// Whether `id` is starred by the current user, checked from an archive
+/// connection.
+pub fn is_favorite<T: Favorite>(
+    conn: &rusqlite::Connection,
+    id: u32,
+) -> Result<bool, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT 1 FROM {VIEWER_SCHEMA}.{} WHERE user = viewer_user() AND id = ?",
+        T::FAVORITES_TABLE
+    ))?;
+    stmt.exists([id])
+}
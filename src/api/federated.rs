@@ -0,0 +1,356 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use axum::{Json, extract::State};
+use axum_extra::extract::Query;
+use futures::future::try_join_all;
+use post_archiver::{
+    FileMeta,
+    query::{BaseFilter, FromQuery},
+};
+use rusqlite::{ToSql, params_from_iter};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use super::{
+    archives::Archives,
+    error::{ApiError, ApiResult},
+    posts::SearchQuery,
+    relation::{RelationTarget, RequireRelations},
+    utils::{Pagination, cursor::CursorValue, post_preview::PostPreview},
+};
+
+/// Most posts a page may list.
+const MAX_LIMIT: u64 = 100;
+/// How far into the results pages may reach. Every archive is read up to the
+/// end of the page asked for, so deep pages cost a lot.
+const MAX_DEPTH: u64 = 10_000;
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ArchivePostPreview {
+    /// Name of the archive the post is from
+    pub archive: String,
+    #[serde(flatten)]
+    #[ts(flatten)]
+    pub post: PostPreview,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct FederatedPosts {
+    pub items: Vec<ArchivePostPreview>,
+    pub total: u64,
+    /// Thumbnails of `items`, by archive
+    pub file_metas: HashMap<String, Vec<FileMeta>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FederatedQuery {
+    /// Archives to search, all of them if empty
+    #[serde(default)]
+    archives: Vec<String>,
+}
+
+/// A post along with where it sorts.
+struct Hit {
+    archive: usize,
+    key: Option<CursorValue>,
+    post: PostPreview,
+}
+
+/// Search every archive at once, merging the results in the order asked for.
+///
+/// Paged by `page` only, as a cursor cannot point into several archives.
+/// Tags, authors and such are left out, their ids differ between archives.
+pub async fn federated_search_handler(
+    Query(pagination): Query<Pagination>,
+    Query(federated): Query<FederatedQuery>,
+    Query(searchs): Query<SearchQuery>,
+    State(archives): State<Archives>,
+) -> ApiResult<Json<FederatedPosts>> {
+    if pagination.cursor.is_some() {
+        return Err(ApiError::bad_request(
+            "Search across archives is paged by page, not cursor",
+        ));
+    }
+    if searchs.has_relation_filters() {
+        return Err(ApiError::bad_request(
+            "Tags, authors, collections and platforms cannot be searched across archives",
+        ));
+    }
+    if let Some(name) = federated
+        .archives
+        .iter()
+        .find(|name| !archives.iter().any(|(archive, _)| &archive.name == *name))
+    {
+        return Err(ApiError::not_found(format!("No archive named {name}")));
+    }
+
+    let (limit, page) = (pagination.limit().min(MAX_LIMIT), pagination.page());
+    let depth = page
+        .checked_add(1)
+        .and_then(|pages| pages.checked_mul(limit))
+        .filter(|&depth| depth <= MAX_DEPTH)
+        .ok_or_else(|| {
+            ApiError::bad_request(format!(
+                "Search across archives only reaches the first {MAX_DEPTH} posts"
+            ))
+        })?;
+    let selected: Vec<usize> = (0..archives.len())
+        .filter(|&i| {
+            federated.archives.is_empty() || federated.archives.contains(&archives[i].0.name)
+        })
+        .collect();
+
+    // Every archive could fill the requested page on its own, so each
+    // contributes all its posts up to the end of it.
+    let searches = selected.iter().map(|&i| {
+        let state = &archives[i].1;
+        let searchs = searchs.clone();
+        async move {
+            if searchs.is_full_text() {
+                state.sync_search().await?;
+            }
+
+            state
+                .with_manager(move |manager| {
+                    let query = searchs.build(manager, None)?;
+                    let total = query.count()?;
+
+                    let mut sql = query.raw_sql::<PostPreview>();
+                    sql.limit_clause = Some([depth, 0]);
+                    let (clauses, params) = sql.build_generic_sql();
+                    // The sort key is read along with each post. The page is
+                    // picked first, so a `:match` in the key is numbered after
+                    // the params of the filters rather than ahead of them.
+                    let (key, dir) = searchs.order();
+                    let mut stmt = manager.conn().prepare_cached(&format!(
+                        "
+                            WITH page AS (SELECT posts.id FROM posts {clauses})
+                            SELECT {}, {key} AS sort_key FROM posts
+                            WHERE posts.id IN (SELECT id FROM page)
+                            ORDER BY sort_key {dir}, posts.id {dir}
+                            ",
+                        PostPreview::columns()
+                    ))?;
+                    let params = params.iter().map(|param| param.as_ref() as &dyn ToSql);
+                    let rows = stmt.query_map(params_from_iter(params), |row| {
+                        // No key for posts without it, e.g. never published
+                        let key: Option<CursorValue> = row.get("sort_key")?;
+                        Ok((key, <PostPreview as FromQuery>::from_row(row)?))
+                    })?;
+                    let (keys, mut posts): (Vec<_>, Vec<_>) =
+                        rows.collect::<Result<Vec<_>, _>>()?.into_iter().unzip();
+                    searchs.add_snippets(manager.conn(), &mut posts)?;
+
+                    let found = keys
+                        .into_iter()
+                        .zip(posts)
+                        .map(|(key, post)| Hit {
+                            archive: i,
+                            key,
+                            post,
+                        })
+                        .collect::<Vec<_>>();
+                    Ok((found, total))
+                })
+                .await
+        }
+    });
+    let (hits, totals): (Vec<Vec<Hit>>, Vec<u64>) =
+        try_join_all(searches).await?.into_iter().unzip();
+    let total = totals.into_iter().sum();
+
+    let merged = merge(hits, searchs.has_comparable_order(), searchs.is_ascending());
+    let items: Vec<Hit> = merged
+        .into_iter()
+        .skip((limit * page) as usize)
+        .take(limit as usize)
+        .collect();
+
+    let thumbs = selected.iter().filter_map(|&i| {
+        let thumbs: Vec<_> = items
+            .iter()
+            .filter(|hit| hit.archive == i)
+            .flat_map(|hit| hit.post.file_metas())
+            .collect();
+        if thumbs.is_empty() {
+            return None;
+        }
+        let (archive, state) = &archives[i];
+        Some(async move {
+            let metas = state
+                .with_manager(move |manager| Ok(FileMeta::query(manager.conn(), thumbs)?))
+                .await?;
+            ApiResult::Ok((archive.name.clone(), metas))
+        })
+    });
+    let file_metas: HashMap<_, _> = try_join_all(thumbs).await?.into_iter().collect();
+
+    let items = items
+        .into_iter()
+        .map(|hit| ArchivePostPreview {
+            archive: archives[hit.archive].0.name.clone(),
+            post: hit.post,
+        })
+        .collect();
+
+    Ok(Json(FederatedPosts {
+        items,
+        total,
+        file_metas,
+    }))
+}
+
+/// Merge the per-archive lists, each already in order. Orders without a
+/// comparable key, i.e. unseeded random or relevance, take turns instead.
+fn merge(hits: Vec<Vec<Hit>>, sorted: bool, ascending: bool) -> Vec<Hit> {
+    if !sorted {
+        let mut lists: Vec<_> = hits.into_iter().map(Vec::into_iter).collect();
+        let mut merged = vec![];
+        loop {
+            let before = merged.len();
+            merged.extend(lists.iter_mut().filter_map(Iterator::next));
+            if merged.len() == before {
+                return merged;
+            }
+        }
+    }
+
+    let mut merged: Vec<Hit> = hits.into_iter().flatten().collect();
+    merged.sort_by(|a, b| {
+        let order = compare_keys(a.key.as_ref(), b.key.as_ref())
+            .then(a.post.id.raw().cmp(&b.post.id.raw()));
+        let order = if ascending { order } else { order.reverse() };
+        order.then(a.archive.cmp(&b.archive))
+    });
+    merged
+}
+
+/// Compare sort keys the way SQLite does: NULL first, then numbers, then
+/// text.
+fn compare_keys(a: Option<&CursorValue>, b: Option<&CursorValue>) -> Ordering {
+    fn number(value: &CursorValue) -> Option<f64> {
+        match value {
+            CursorValue::Integer(value) => Some(*value as f64),
+            CursorValue::Real(value) => Some(*value),
+            CursorValue::Text(_) => None,
+        }
+    }
+
+    match (a, b) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Less,
+        (Some(_), None) => Ordering::Greater,
+        (Some(CursorValue::Integer(a)), Some(CursorValue::Integer(b))) => a.cmp(b),
+        (Some(CursorValue::Text(a)), Some(CursorValue::Text(b))) => a.cmp(b),
+        (Some(a), Some(b)) => match (number(a), number(b)) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use post_archiver::PostId;
+
+    use super::*;
+
+    fn hit(archive: usize, id: u32, key: Option<CursorValue>) -> Hit {
+        Hit {
+            archive,
+            key,
+            post: PostPreview {
+                id: PostId::new(id),
+                title: String::new(),
+                thumb: None,
+                updated: DateTime::UNIX_EPOCH,
+                read_at: None,
+                snippet: None,
+            },
+        }
+    }
+
+    /// `(archive, id)` of each hit, in order.
+    fn ids(hits: &[Hit]) -> Vec<(usize, u32)> {
+        hits.iter()
+            .map(|hit| (hit.archive, hit.post.id.raw()))
+            .collect()
+    }
+
+    #[test]
+    fn compare_keys_orders_like_sqlite() {
+        let int = CursorValue::Integer;
+        let text = |value: &str| CursorValue::Text(value.to_string());
+
+        assert_eq!(compare_keys(None, Some(&int(0))), Ordering::Less);
+        assert_eq!(
+            compare_keys(Some(&int(9)), Some(&text("0"))),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_keys(Some(&text("a")), Some(&text("b"))),
+            Ordering::Less
+        );
+        assert_eq!(compare_keys(Some(&int(2)), Some(&int(10))), Ordering::Less);
+        assert_eq!(compare_keys(None, None), Ordering::Equal);
+    }
+
+    #[test]
+    fn compare_keys_mixes_integers_and_reals() {
+        let (one, half, two) = (
+            CursorValue::Integer(1),
+            CursorValue::Real(1.5),
+            CursorValue::Integer(2),
+        );
+        assert_eq!(compare_keys(Some(&one), Some(&half)), Ordering::Less);
+        assert_eq!(compare_keys(Some(&two), Some(&half)), Ordering::Greater);
+        assert_eq!(
+            compare_keys(Some(&CursorValue::Real(2.0)), Some(&two)),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn merge_sorts_by_key_then_id_then_archive() {
+        let key = |value| Some(CursorValue::Integer(value));
+        let hits = vec![
+            vec![hit(0, 5, key(30)), hit(0, 3, key(20)), hit(0, 1, None)],
+            vec![hit(1, 4, key(30)), hit(1, 3, key(20)), hit(1, 2, key(10))],
+        ];
+
+        let merged = merge(hits, true, false);
+        assert_eq!(
+            ids(&merged),
+            [(0, 5), (1, 4), (0, 3), (1, 3), (1, 2), (0, 1)]
+        );
+    }
+
+    #[test]
+    fn merge_sorts_ascending() {
+        let key = |value| Some(CursorValue::Integer(value));
+        let hits = vec![
+            vec![hit(0, 1, key(10)), hit(0, 2, key(40))],
+            vec![hit(1, 1, None), hit(1, 2, key(20))],
+        ];
+
+        let merged = merge(hits, true, true);
+        assert_eq!(ids(&merged), [(1, 1), (0, 1), (1, 2), (0, 2)]);
+    }
+
+    #[test]
+    fn merge_takes_turns_without_key() {
+        let hits = vec![
+            vec![hit(0, 7, None), hit(0, 3, None), hit(0, 9, None)],
+            vec![hit(1, 2, None)],
+            vec![],
+        ];
+
+        let merged = merge(hits, false, false);
+        assert_eq!(ids(&merged), [(0, 7), (1, 2), (0, 3), (0, 9)]);
+    }
+}
@@ -0,0 +1,133 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+};
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use post_archiver::{FileMeta, PostId, query::Totalled};
+use serde::Serialize;
+use ts_rs::TS;
+
+use crate::config::PublicConfig;
+
+use super::{
+    AppState,
+    error::{ApiError, ApiResult},
+    relation::RelationTarget,
+};
+
+/// Characters escaped in each path segment of a resource URL.
+pub const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct PostFile {
+    #[serde(flatten)]
+    pub meta: FileMeta,
+    /// Size on disk in bytes, absent if the file is missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub height: Option<u32>,
+    /// Where to download the original file from
+    pub url: String,
+}
+
+/// Every file of a post, including its thumbnail.
+pub async fn post_files_handler(
+    Path(id): Path<PostId>,
+    State(state): State<AppState>,
+) -> ApiResult<Json<Totalled<Vec<PostFile>>>> {
+    let public = state.public.clone();
+    let base = state.base.clone();
+
+    state
+        .with_manager(move |manager| {
+            let files = list_post_files(manager.conn(), id)?
+                .ok_or_else(|| ApiError::not_found("Post not found"))?;
+
+            let items: Vec<PostFile> = files
+                .into_iter()
+                .map(|meta| {
+                    let path = manager.path.join(meta.path());
+                    let size = std::fs::metadata(&path).ok().map(|metadata| metadata.len());
+
+                    let extra = |key: &str| meta.extra.get(key).and_then(|v| v.as_u64());
+                    let (width, height) = match (extra("width"), extra("height")) {
+                        (Some(width), Some(height)) => (Some(width as u32), Some(height as u32)),
+                        // Only the header is read, not the whole image
+                        _ if meta.mime.starts_with("image/") && size.is_some() => {
+                            image::image_dimensions(&path)
+                                .map_or((None, None), |(w, h)| (Some(w), Some(h)))
+                        }
+                        _ => (None, None),
+                    };
+
+                    let url = resource_url(&public, &base, &meta);
+                    PostFile {
+                        meta,
+                        size,
+                        width,
+                        height,
+                        url,
+                    }
+                })
+                .collect();
+
+            let total = items.len() as u64;
+            Ok(Json(Totalled { items, total }))
+        })
+        .await
+}
+
+/// The files of post `id` and its thumbnail, `None` if the post does not exist.
+pub fn list_post_files(
+    conn: &rusqlite::Connection,
+    id: PostId,
+) -> Result<Option<Vec<FileMeta>>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached("SELECT thumb FROM posts WHERE id = ?")?;
+    let Some(thumb) = stmt
+        .query_map([id], |row| row.get::<_, Option<u32>>(0))?
+        .next()
+        .transpose()?
+    else {
+        return Ok(None);
+    };
+
+    let mut stmt = conn.prepare_cached(
+        "SELECT * FROM file_metas WHERE post = ? OR id IS ? ORDER BY post != ?, id",
+    )?;
+    let files = stmt.query_map((id, thumb, id), <FileMeta as RelationTarget>::from_row)?;
+    files.collect::<Result<_, _>>().map(Some)
+}
+
+/// URL of the original file, under `resource_url` if it is served elsewhere
+/// or else this server's `resource` route of the archive at `base`.
+pub fn resource_url(public: &PublicConfig, base: &str, meta: &FileMeta) -> String {
+    let base = match public.resource_url.as_deref() {
+        Some(url) => url.trim_end_matches('/').to_string(),
+        None => format!("{base}/resource"),
+    };
+    let path = meta
+        .path()
+        .iter()
+        .map(|segment| utf8_percent_encode(&segment.to_string_lossy(), PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+    format!("{base}/{path}")
+}
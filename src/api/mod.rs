@@ -1,95 +1,291 @@
+pub mod accounts;
+pub mod archives;
 pub mod category;
+pub mod download;
+pub mod error;
+pub mod favorites;
+pub mod federated;
+pub mod files;
+pub mod pool;
 pub mod post;
 pub mod posts;
+pub mod reads;
+pub mod related;
 pub mod relation;
+pub mod saved_searches;
+pub mod search;
+pub mod snippet;
 pub mod summary;
 pub mod utils;
+pub mod viewer;
+pub mod watch;
 
 use std::{
+    io,
     path::Path,
     sync::{Arc, Mutex},
 };
 
+use archives::get_archives_api;
 use axum::{
     Router,
     extract::{Query, State},
-    http::StatusCode,
+    middleware,
     response::Redirect,
     routing::get,
 };
 use cached::TimedCache;
 use category::Category;
-use post_archiver::{Author, Collection, Platform, Tag, manager::PostArchiverManager};
+use error::{ApiError, ApiResult, json_rejections, log_errors};
+use federated::federated_search_handler;
+use pool::ArchiveConnections;
+use post_archiver::{
+    Author, Collection, Platform, Tag,
+    manager::{ManagerCaches, PostArchiverManager},
+};
+use r2d2::Pool;
+use rusqlite::{Connection, OptionalExtension, functions::FunctionFlags};
+use search::SearchIndex;
 use serde::Deserialize;
-use url::Url;
 use summary::get_summary_api;
+use tokio::sync::broadcast;
+use tracing::error;
+use url::Url;
+use viewer::{ViewerDatabase, as_user};
+use watch::{EVENTS_CAPACITY, PostsChanged, events_handler, watch_archive};
 
-use crate::config::Config;
+use crate::{
+    config::{Archive, Config, PublicConfig},
+    shutdown::Shutdown,
+};
 
 #[derive(Clone)]
 pub struct AppState {
-    manager: Arc<Mutex<PostArchiverManager>>,
+    pool: Pool<ArchiveConnections>,
     caches: Arc<Caches>,
+    search: Arc<SearchIndex>,
+    viewer: Arc<ViewerDatabase>,
+    /// Viewer database of the main archive, which holds the accounts of
+    /// every archive
+    accounts: Arc<ViewerDatabase>,
+    /// Prefix of the archive's routes, see [`Archive::base`]
+    base: Arc<str>,
+    public: Arc<PublicConfig>,
+    events: broadcast::Sender<PostsChanged>,
+    shutdown: Shutdown,
 }
 
 #[derive(Debug)]
 pub struct Caches {
     pub tables: Mutex<TimedCache<&'static str, u64>>,
+    /// post_archiver's own caches, shared by every pooled connection
+    pub manager: Arc<Mutex<ManagerCaches>>,
 }
 
 impl AppState {
-    pub fn manager(&self) -> std::sync::MutexGuard<'_, PostArchiverManager> {
-        self.manager.lock().unwrap()
+    /// Run `f` with a pooled archive connection on the blocking thread pool,
+    /// so queries neither stall the async runtime nor wait on each other.
+    pub async fn with_manager<T, F>(&self, f: F) -> ApiResult<T>
+    where
+        F: FnOnce(&PostArchiverManager) -> ApiResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        let user = accounts::current_user();
+        tokio::task::spawn_blocking(move || {
+            let manager = pool.get()?;
+            as_user(user, || f(&manager))
+        })
+        .await?
+    }
+
+    /// Run `f` with the writable viewer database on the blocking thread pool.
+    pub async fn with_viewer<T, F>(&self, f: F) -> ApiResult<T>
+    where
+        F: FnOnce(&Connection) -> ApiResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let viewer = self.viewer.clone();
+        tokio::task::spawn_blocking(move || f(&viewer.conn())).await?
+    }
+
+    /// Run `f` with the database holding users and sessions on the blocking
+    /// thread pool.
+    pub async fn with_accounts<T, F>(&self, f: F) -> ApiResult<T>
+    where
+        F: FnOnce(&Connection) -> ApiResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let accounts = self.accounts.clone();
+        tokio::task::spawn_blocking(move || f(&accounts.conn())).await?
+    }
+
+    /// Bring the search index up to date before a full-text query.
+    pub async fn sync_search(&self) -> ApiResult<()> {
+        let search = self.search.clone();
+        tokio::task::spawn_blocking(move || search.sync_if_due())
+            .await?
+            .map_err(ApiError::from)
     }
 }
 
-pub fn get_api_router(config: &Config) -> Router<()> {
-    let path = config.path.clone();
+/// One API router per archive, in the same order. Accounts are shared, so
+/// their routes, `/archives` and `/search` across archives are only on the
+/// first.
+pub fn get_api_routers(
+    config: &Config,
+    archives: &[Archive],
+    shutdown: Shutdown,
+) -> Vec<Router<()>> {
+    let mut states: Vec<AppState> = vec![];
+    for archive in archives {
+        let accounts = states.first().map(|state| state.viewer.clone());
+        states.push(archive_state(archive, accounts, shutdown.clone()));
+    }
+
+    let listed = Arc::new(
+        archives
+            .iter()
+            .zip(&states)
+            .map(|(archive, state)| (archive.clone(), state.clone()))
+            .collect::<Vec<_>>(),
+    );
+
+    states
+        .into_iter()
+        .enumerate()
+        .map(|(i, state)| {
+            let router = Router::new()
+                .route("/summary", get(get_summary_api))
+                .route("/redirect", get(get_redirect_api))
+                .route("/events", get(events_handler));
+
+            let router = if i > 0 {
+                router
+            } else {
+                let router = router
+                    .route_service(
+                        "/archives",
+                        get(get_archives_api).with_state(listed.clone()),
+                    )
+                    .route_service(
+                        "/search",
+                        get(federated_search_handler).with_state(listed.clone()),
+                    );
+                if config.public.accounts {
+                    accounts::wrap_accounts_route(router)
+                } else {
+                    router
+                }
+            };
+            let router = posts::wrap_posts_route(router);
+            let router = favorites::wrap_favorites_route(router);
+            let router = reads::wrap_reads_route(router);
+            let router = saved_searches::wrap_saved_searches_route(router);
+            let router = Tag::wrap_category_route(router);
+            let router = Author::wrap_category_route(router);
+            let router = Platform::wrap_category_route(router);
+            let router = Collection::wrap_category_route(router);
+
+            router
+                .fallback(|| async { ApiError::not_found("No such API endpoint") })
+                .layer(middleware::from_fn(json_rejections))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    accounts::sessions,
+                ))
+                .layer(middleware::from_fn(log_errors))
+                .with_state(state)
+        })
+        .collect()
+}
+
+/// Open everything `archive` is served with. Its own viewer database holds
+/// the accounts unless `accounts` are given.
+fn archive_state(
+    archive: &Archive,
+    accounts: Option<Arc<ViewerDatabase>>,
+    shutdown: Shutdown,
+) -> AppState {
+    let path = archive.path.clone();
+
+    let viewer =
+        Arc::new(ViewerDatabase::open(&archive.data_dir).expect("failed to open viewer database"));
+    let search =
+        Arc::new(SearchIndex::open(&archive.data_dir, &path).expect("failed to open search index"));
 
-    #[allow(unused_mut)]
-    let mut manager = connect_database(path.as_path());
+    // The first build can take a while on large archives, so do it off the
+    // request path. Later syncs are incremental and run on demand.
+    let indexer = search.clone();
+    let name = archive.name.clone();
+    std::thread::spawn(move || {
+        if let Err(err) = indexer.sync() {
+            error!("Failed to build full-text search index of {name}: {err}");
+        }
+    });
+
+    let manager_caches = Arc::new(Mutex::new(ManagerCaches::default()));
+    let pool =
+        ArchiveConnections::new(path, viewer.clone(), search.clone(), manager_caches.clone())
+            .pool()
+            .expect("failed to open archive");
 
-    let manager = Arc::new(Mutex::new(manager));
     let state = AppState {
         caches: Arc::new(Caches {
             tables: Mutex::new(TimedCache::with_lifespan(60 * 60 * 12)),
+            manager: manager_caches,
         }),
-        manager,
+        pool,
+        search,
+        accounts: accounts.unwrap_or_else(|| viewer.clone()),
+        viewer,
+        base: archive.base.as_str().into(),
+        public: Arc::new(archive.public.clone()),
+        events: broadcast::channel(EVENTS_CAPACITY).0,
+        shutdown,
     };
 
-    let router = Router::new()
-        .route("/summary", get(get_summary_api))
-        .route("/redirect", get(get_redirect_api));
-
-    let router = posts::wrap_posts_route(router);
-    let router = Tag::wrap_category_route(router);
-    let router = Author::wrap_category_route(router);
-    let router = Platform::wrap_category_route(router);
-    let router = Collection::wrap_category_route(router);
+    if let Err(err) = watch_archive(&archive.path, state.clone()) {
+        error!("Failed to watch {} for changes: {err}", archive.name);
+    }
 
-    router.fallback(StatusCode::NOT_FOUND).with_state(state)
+    state
 }
 
-pub fn connect_database(path: &Path) -> PostArchiverManager {
-    let manager = PostArchiverManager::open(path).unwrap().unwrap();
+pub fn connect_database(path: &Path) -> post_archiver::error::Result<PostArchiverManager> {
+    let manager = PostArchiverManager::open(path)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no archive database found"))?;
 
     // Tune SQLite for a read-mostly viewer workload. All of these are
     // per-connection pragmas that do NOT modify the database file, so they are
     // safe even though the archive is owned/written by the archiver process.
-    manager
-        .conn()
-        .execute_batch(
-            "
-            PRAGMA query_only = ON;       -- read-only guard: reject any accidental writes
-            PRAGMA busy_timeout = 5000;   -- wait up to 5s on a lock instead of erroring
-            PRAGMA cache_size = -65536;   -- ~64MB page cache (negative value = KiB)
-            PRAGMA mmap_size = 268435456; -- 256MB memory-mapped reads, fewer syscalls
-            PRAGMA temp_store = MEMORY;   -- keep sorts/temp tables in memory
-            ",
-        )
-        .expect("failed to apply SQLite pragmas");
-
-    manager
+    manager.conn().execute_batch(
+        "
+        PRAGMA query_only = ON;       -- read-only guard: reject any accidental writes
+        PRAGMA busy_timeout = 5000;   -- wait up to 5s on a lock instead of erroring
+        PRAGMA cache_size = -65536;   -- ~64MB page cache (negative value = KiB)
+        PRAGMA mmap_size = 268435456; -- 256MB memory-mapped reads, fewer syscalls
+        PRAGMA temp_store = MEMORY;   -- keep sorts/temp tables in memory
+        ",
+    )?;
+
+    manager.conn().create_scalar_function(
+        "seeded_random",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| Ok(seeded_random(ctx.get(0)?, ctx.get(1)?)),
+    )?;
+
+    Ok(manager)
+}
+
+/// Stable pseudo-random sort key for `id` under `seed` (SplitMix64), so a
+/// shuffled list keeps its order across pages.
+fn seeded_random(id: i64, seed: i64) -> i64 {
+    let mut z = (id as u64) ^ (seed as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    (z ^ (z >> 31)) as i64
 }
 
 #[derive(Debug, Deserialize)]
@@ -100,27 +296,62 @@ pub struct RedirectQuery {
 async fn get_redirect_api(
     Query(query): Query<RedirectQuery>,
     State(state): State<AppState>,
-) -> Result<Redirect, StatusCode> {
+) -> ApiResult<Redirect> {
     let url = query.url;
 
     let parsed = Url::parse(&url);
     let scheme = parsed.as_ref().map(Url::scheme);
     if !matches!(scheme, Ok("http") | Ok("https")) {
-        return Err(StatusCode::BAD_REQUEST);
+        return Err(ApiError::bad_request(
+            "Only http and https URLs can be redirected",
+        ));
     }
 
-    let manager = state.manager();
-    let conn = manager.conn();
+    state
+        .with_manager(move |manager| {
+            let id: Option<u32> = manager
+                .conn()
+                .prepare_cached("SELECT id FROM posts WHERE source = ?")?
+                .query_row([&url], |row| row.get(0))
+                .optional()?;
 
-    let mut stmt = conn
-        .prepare_cached("SELECT id FROM posts WHERE source = ?")
-        .unwrap();
-    let id: Option<u32> = stmt.query_row([&url], |row| row.get(0)).ok();
+            let url = match id {
+                Some(id) => format!("/posts/{id}"),
+                None => url,
+            };
 
-    let url = match id {
-        Some(id) => format!("/posts/{id}"),
-        None => url,
-    };
+            Ok(Redirect::permanent(&url))
+        })
+        .await
+}
 
-    Ok(Redirect::permanent(&url))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shuffled(seed: i64) -> Vec<i64> {
+        let mut ids: Vec<i64> = (1..=50).collect();
+        ids.sort_by_key(|&id| seeded_random(id, seed));
+        ids
+    }
+
+    #[test]
+    fn seeded_random_is_stable() {
+        assert_eq!(seeded_random(7, 42), seeded_random(7, 42));
+        assert_eq!(shuffled(42), shuffled(42));
+    }
+
+    #[test]
+    fn seeded_random_depends_on_seed() {
+        assert_ne!(shuffled(1), shuffled(2));
+        assert_ne!(shuffled(1), (1..=50).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn seeded_random_has_no_collisions_on_small_ids() {
+        let mut keys: Vec<i64> = (0..10_000).map(|id| seeded_random(id, 0)).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        assert_eq!(keys.len(), 10_000);
+    }
 }
@@ -0,0 +1,81 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use post_archiver::{
+    error::Error,
+    manager::{ManagerCaches, PostArchiverManager},
+};
+use r2d2::{ManageConnection, Pool};
+
+use super::{connect_database, search::SearchIndex, viewer::ViewerDatabase};
+
+/// Connections kept open even when idle, so the first requests after a quiet
+/// period don't pay for reopening the archive.
+const MIN_IDLE: u32 = 2;
+/// How long a request waits for a free connection before giving up.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Opens read-only archive connections for the pool, each set up like
+/// [`connect_database`] and with the viewer database and search index attached.
+///
+/// The connections share one set of post_archiver caches, so a count cached
+/// on one is reused by the others and can be dropped for all of them at once.
+#[derive(Debug)]
+pub struct ArchiveConnections {
+    path: PathBuf,
+    viewer: Arc<ViewerDatabase>,
+    search: Arc<SearchIndex>,
+    caches: Arc<Mutex<ManagerCaches>>,
+}
+
+impl ArchiveConnections {
+    pub fn new(
+        path: PathBuf,
+        viewer: Arc<ViewerDatabase>,
+        search: Arc<SearchIndex>,
+        caches: Arc<Mutex<ManagerCaches>>,
+    ) -> Self {
+        Self {
+            path,
+            viewer,
+            search,
+            caches,
+        }
+    }
+
+    /// A pool sized so every core can run a query at once.
+    pub fn pool(self) -> Result<Pool<Self>, r2d2::Error> {
+        let size = thread::available_parallelism().map_or(4, |n| n.get() as u32);
+        Pool::builder()
+            .max_size(size.max(MIN_IDLE))
+            .min_idle(Some(MIN_IDLE))
+            .connection_timeout(CONNECTION_TIMEOUT)
+            .build(self)
+    }
+}
+
+impl ManageConnection for ArchiveConnections {
+    type Connection = PostArchiverManager;
+    type Error = Error;
+
+    fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        let mut manager = connect_database(&self.path)?;
+        self.viewer.attach(manager.conn())?;
+        self.search.attach(manager.conn())?;
+        manager.caches = self.caches.clone();
+        Ok(manager)
+    }
+
+    fn is_valid(&self, manager: &mut Self::Connection) -> Result<(), Self::Error> {
+        manager.conn().execute_batch("SELECT 1")?;
+        Ok(())
+    }
+
+    fn has_broken(&self, _: &mut Self::Connection) -> bool {
+        false
+    }
+}
@@ -1,18 +1,25 @@
 use axum::{
     Json,
     extract::{Path, State},
-    http::StatusCode,
 };
 use chrono::{DateTime, Utc};
 use post_archiver::{
     Author, Collection, Comment, Content, FileMetaId, PlatformId, PostId, Tag, query::Query,
 };
 use serde::Serialize;
+use tracing::warn;
 use ts_rs::TS;
 
 use crate::api::AppState;
 
-use super::relation::{RequireRelations, WithRelations};
+use super::{
+    accounts::User,
+    error::{ApiError, ApiResult},
+    favorites::is_favorite,
+    reads::{ReadProgress, read_state, record_read},
+    relation::{RequireRelations, WithRelations},
+    utils::post_preview::PostPreview,
+};
 
 #[derive(Debug, Clone, Serialize, TS)]
 #[ts(export)]
@@ -30,6 +37,11 @@ pub struct PostResponse {
     pub authors: Vec<Author>,
     pub collections: Vec<Collection>,
     pub comments: Vec<Comment>,
+
+    pub favorited: bool,
+    /// When the post was opened before this time, `null` if never
+    pub read_at: Option<DateTime<Utc>>,
+    pub progress: Option<ReadProgress>,
 }
 
 impl RequireRelations for PostResponse {
@@ -56,57 +68,69 @@ impl RequireRelations for PostResponse {
 
 pub async fn get_post_handler(
     Path(id): Path<PostId>,
+    user: Option<User>,
     State(state): State<AppState>,
-) -> Result<Json<WithRelations<PostResponse>>, StatusCode> {
-    let manager = state.manager();
+) -> ApiResult<Json<WithRelations<PostResponse>>> {
+    let post = state
+        .with_manager(move |manager| {
+            let Some(post) = manager.get_post(id)? else {
+                return Err(ApiError::not_found("Post not found"));
+            };
 
-    let Some(post) = manager
-        .get_post(id)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    else {
-        return Err(StatusCode::NOT_FOUND);
-    };
+            let binded_post = manager.bind(id);
 
-    let binded_post = manager.bind(id);
-
-    macro_rules! query_relation {
-        ($list_method:ident, $query_method:ident) => {{
-            let ids = binded_post
-                .$list_method()
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            if ids.is_empty() {
-                Vec::new()
-            } else {
-                let mut query = manager.$query_method();
-                query.ids.extend(ids);
-                query
-                    .query()
-                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            macro_rules! query_relation {
+                ($list_method:ident, $query_method:ident) => {{
+                    let ids = binded_post.$list_method()?;
+                    if ids.is_empty() {
+                        Vec::new()
+                    } else {
+                        let mut query = manager.$query_method();
+                        query.ids.extend(ids);
+                        query.query()?
+                    }
+                }};
             }
-        }};
-    }
 
-    let tags = query_relation!(list_tags, tags);
-    let authors = query_relation!(list_authors, authors);
-    let collections = query_relation!(list_collections, collections);
+            let tags = query_relation!(list_tags, tags);
+            let authors = query_relation!(list_authors, authors);
+            let collections = query_relation!(list_collections, collections);
+            let favorited = is_favorite::<PostPreview>(manager.conn(), id.raw())?;
+            let (read_at, progress) = read_state(manager.conn(), id)?.unzip();
+
+            WithRelations::new(
+                manager,
+                PostResponse {
+                    id: post.id,
+                    title: post.title,
+                    content: post.content,
+                    thumb: post.thumb,
+                    platform: post.platform,
+                    source: post.source,
+                    updated: post.updated,
+                    published: post.published,
+                    comments: post.comments,
+                    tags,
+                    authors,
+                    collections,
+                    favorited,
+                    read_at,
+                    progress,
+                },
+            )
+            .map_err(ApiError::from)
+            .map(Json::from)
+        })
+        .await?;
+
+    // Losing track of a read is not worth failing the page over
+    if let Some(User(user)) = user
+        && let Err(err) = state
+            .with_viewer(move |conn| Ok(record_read(conn, user, id)?))
+            .await
+    {
+        warn!("Failed to record post {id} as read: {err:?}");
+    }
 
-    WithRelations::new(
-        &manager,
-        PostResponse {
-            id: post.id,
-            title: post.title,
-            content: post.content,
-            thumb: post.thumb,
-            platform: post.platform,
-            source: post.source,
-            updated: post.updated,
-            published: post.published,
-            comments: post.comments,
-            tags,
-            authors,
-            collections,
-        },
-    )
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
-    .map(Json::from)
+    Ok(post)
 }
@@ -1,22 +1,51 @@
-use axum::{Json, Router, extract::State, http::StatusCode, routing::get};
+use std::rc::Rc;
+
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    routing::get,
+};
 use axum_extra::extract::Query;
 use post_archiver::{
-    AuthorId, CollectionId, PlatformId, TagId,
-    query::{Countable, Paginate, SortDir, Sortable, Totalled, post::PostSort},
+    AuthorId, CollectionId, FileMetaId, PlatformId, PostId, TagId,
+    manager::PostArchiverManager,
+    query::{
+        Countable, Paginate, Param, SortDir, Totalled,
+        post::{PostQuery, PostSort},
+    },
 };
+use rusqlite::{Connection, named_params};
 use serde::{Deserialize, Serialize};
+use ts_rs::TS;
 
 use super::{
     AppState,
+    download::download_post_handler,
+    error::{ApiError, ApiResult},
+    files::post_files_handler,
     post::get_post_handler,
-    relation::WithRelations,
-    utils::{Pagination, post_preview::PostPreview},
+    related::related_posts_handler,
+    relation::{RequireRelations, WithRelations},
+    search::{SEARCH_SCHEMA, SearchIndex},
+    snippet::Snippet,
+    utils::{
+        Pagination, TimeBound,
+        cursor::{Cursor, CursorValue},
+        filtered::Filtered,
+        post_preview::PostPreview,
+        with_cursor::WithCursor,
+    },
+    viewer::{VIEWER_SCHEMA, query_user},
 };
 
 pub fn wrap_posts_route(router: Router<AppState>) -> Router<AppState> {
     router
         .route("/posts", get(list_posts_handler))
         .route("/posts/{id}", get(get_post_handler))
+        .route("/posts/{id}/neighbors", get(post_neighbors_handler))
+        .route("/posts/{id}/related", get(related_posts_handler))
+        .route("/posts/{id}/files", get(post_files_handler))
+        .route("/posts/{id}/download", get(download_post_handler))
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
@@ -25,13 +54,61 @@ pub enum PostOrderBy {
     Id,
     #[default]
     Updated,
+    Published,
+    Title,
+    /// Number of files attached to the post
+    Files,
+    /// Number of top-level comments
+    Comments,
+    /// Shuffled, reproducibly when a `seed` is given
     Random,
+    /// Full-text search rank, falls back to `Updated` without a `match`
+    Relevance,
+}
+
+impl PostOrderBy {
+    fn default_dir(self) -> OrderDir {
+        match self {
+            PostOrderBy::Title => OrderDir::Asc,
+            _ => OrderDir::Desc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderDir {
+    Asc,
+    Desc,
+}
+
+impl From<OrderDir> for SortDir {
+    fn from(dir: OrderDir) -> Self {
+        match dir {
+            OrderDir::Asc => SortDir::Asc,
+            OrderDir::Desc => SortDir::Desc,
+        }
+    }
+}
+
+/// How several ids given for one relation are combined.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// Posts related to every given id
+    #[default]
+    All,
+    /// Posts related to at least one given id
+    Any,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Default)]
 pub struct SearchQuery {
     #[serde(default)]
     search: String,
+    /// FTS5 query over titles and text content, e.g. `"blue sky" OR drag*`
+    #[serde(default, rename = "match")]
+    r#match: String,
     #[serde(default)]
     tags: Vec<TagId>,
     #[serde(default)]
@@ -41,37 +118,643 @@ pub struct SearchQuery {
     #[serde(default)]
     platforms: Vec<PlatformId>,
     #[serde(default)]
+    tags_mode: MatchMode,
+    #[serde(default)]
+    collections_mode: MatchMode,
+    #[serde(default)]
+    authors_mode: MatchMode,
+    // A post has a single platform, so `platforms` always matches any of them
+    #[serde(default)]
+    exclude_tags: Vec<TagId>,
+    #[serde(default)]
+    exclude_collections: Vec<CollectionId>,
+    #[serde(default)]
+    exclude_authors: Vec<AuthorId>,
+    #[serde(default)]
+    exclude_platforms: Vec<PlatformId>,
+    published_after: Option<TimeBound>,
+    published_before: Option<TimeBound>,
+    updated_after: Option<TimeBound>,
+    updated_before: Option<TimeBound>,
+    #[serde(default)]
     order_by: PostOrderBy,
+    /// Defaults to ascending for `title`, descending otherwise
+    dir: Option<OrderDir>,
+    /// Seed for `order_by=random`, so paging through the shuffle is stable
+    seed: Option<i64>,
+    /// Only starred posts
+    #[serde(default)]
+    favorited: bool,
+    /// Only posts never opened
+    #[serde(default)]
+    unread: bool,
+}
+
+impl SearchQuery {
+    /// Build the posts query for these filters and order, starting after
+    /// `cursor` if given, before pagination.
+    ///
+    /// `match` expressions are validated here, so the search index should be
+    /// synced beforehand.
+    pub fn build<'a>(
+        &self,
+        manager: &'a PostArchiverManager,
+        cursor: Option<&Cursor>,
+    ) -> ApiResult<Filtered<PostQuery<'a, Connection>>> {
+        let mut query = manager.posts();
+
+        query.title.contains(&self.search);
+        query.platforms.extend(self.platforms.clone());
+        if let Some(after) = self.published_after {
+            query.published.after(after.start());
+        }
+        if let Some(before) = self.published_before {
+            query.published.before(before.end());
+        }
+        if let Some(after) = self.updated_after {
+            query.updated.after(after.start());
+        }
+        if let Some(before) = self.updated_before {
+            query.updated.before(before.end());
+        }
+        if self.authors_mode == MatchMode::All {
+            query.authors.extend(self.authors.clone());
+        }
+        if self.tags_mode == MatchMode::All {
+            query.tags.extend(self.tags.clone());
+        }
+        if self.collections_mode == MatchMode::All {
+            query.collections.extend(self.collections.clone());
+        }
+
+        let mut query = Filtered::new(query);
+
+        filter_relation(
+            &mut query,
+            ("author_posts", "author"),
+            (&self.authors, self.authors_mode),
+            &self.exclude_authors,
+        );
+        filter_relation(
+            &mut query,
+            ("post_tags", "tag"),
+            (&self.tags, self.tags_mode),
+            &self.exclude_tags,
+        );
+        filter_relation(
+            &mut query,
+            ("collection_posts", "collection"),
+            (&self.collections, self.collections_mode),
+            &self.exclude_collections,
+        );
+        if !self.exclude_platforms.is_empty() {
+            query.filter(
+                "(platform IS NULL OR platform NOT IN (SELECT value FROM json_each(?)))",
+                [json_param(&self.exclude_platforms)],
+            );
+        }
+
+        if !self.r#match.is_empty() {
+            SearchIndex::check_match(manager.conn(), &self.r#match).map_err(|err| {
+                ApiError::bad_request("Invalid match expression").with_source(err)
+            })?;
+
+            query.filter(
+                format!("posts.id IN (SELECT rowid FROM {SEARCH_SCHEMA}.posts_fts WHERE posts_fts MATCH :match)"),
+                [Rc::new(self.r#match.clone()) as Param],
+            );
+        }
+
+        // Logged out, nothing is favorited and everything is unread
+        let user = query_user();
+        if self.favorited {
+            match user {
+                Some(user) => query.filter_per_viewer(
+                    format!(
+                        "posts.id IN (SELECT id FROM {VIEWER_SCHEMA}.favorite_posts WHERE user = ?)"
+                    ),
+                    [Rc::new(user) as Param],
+                ),
+                None => query.filter("FALSE", []),
+            };
+        }
+
+        if self.unread
+            && let Some(user) = user
+        {
+            query.filter_per_viewer(
+                format!(
+                    "posts.id NOT IN (SELECT id FROM {VIEWER_SCHEMA}.post_reads WHERE user = ?)"
+                ),
+                [Rc::new(user) as Param],
+            );
+        }
+
+        let (column, dir) = self.order();
+        query.order(format!("{column} {dir}"));
+        // Break ties so pages don't overlap
+        if self.order_by != PostOrderBy::Id {
+            query.order(format!("posts.id {dir}"));
+        }
+
+        if let Some(cursor) = cursor {
+            query.seek(
+                Cursor::seek_clause(&column, "posts.id", dir),
+                [Rc::new(cursor.value.clone()) as Param, Rc::new(cursor.id)],
+            );
+        }
+
+        Ok(query)
+    }
+
+    /// Whether posts are filtered by a full-text `match` query, which needs
+    /// the search index synced before building.
+    pub fn is_full_text(&self) -> bool {
+        !self.r#match.is_empty()
+    }
+
+    /// Whether posts are filtered by tags, authors, collections or platforms,
+    /// whose ids only mean something within one archive.
+    pub fn has_relation_filters(&self) -> bool {
+        !(self.tags.is_empty()
+            && self.collections.is_empty()
+            && self.authors.is_empty()
+            && self.platforms.is_empty()
+            && self.exclude_tags.is_empty()
+            && self.exclude_collections.is_empty()
+            && self.exclude_authors.is_empty()
+            && self.exclude_platforms.is_empty())
+    }
+
+    /// Whether the sort keys of posts from different archives can be compared.
+    /// Relevance ranks depend on the rest of the index they come from.
+    pub fn has_comparable_order(&self) -> bool {
+        let ranked = self.order_by == PostOrderBy::Relevance && self.is_full_text();
+        self.cursor_order().is_some() && !ranked
+    }
+
+    /// Whether posts are listed in ascending order.
+    pub fn is_ascending(&self) -> bool {
+        self.dir.unwrap_or(self.order_by.default_dir()) == OrderDir::Asc
+    }
+
+    /// Fill in the snippets of `posts` matching the search term or `match`
+    /// expression.
+    pub fn add_snippets(
+        &self,
+        conn: &Connection,
+        posts: &mut [PostPreview],
+    ) -> Result<(), rusqlite::Error> {
+        let ids = posts.iter().map(|post| post.id);
+        let mut snippets = if !self.r#match.is_empty() {
+            Snippet::full_text(conn, &self.r#match, ids)
+        } else if !self.search.is_empty() {
+            Snippet::plain(conn, &self.search, ids)
+        } else {
+            return Ok(());
+        }?;
+        for post in posts.iter_mut() {
+            post.snippet = snippets.remove(&post.id);
+        }
+        Ok(())
+    }
+
+    /// The sort expression and its direction.
+    pub fn order(&self) -> (String, &'static str) {
+        let dir = SortDir::from(self.dir.unwrap_or(self.order_by.default_dir())).as_sql();
+        let column = match self.order_by {
+            PostOrderBy::Id => PostSort::Id.to_string(),
+            PostOrderBy::Updated => PostSort::Updated.to_string(),
+            PostOrderBy::Published => PostSort::Published.to_string(),
+            PostOrderBy::Title => PostSort::Title.to_string(),
+            PostOrderBy::Files => {
+                "(SELECT COUNT() FROM file_metas WHERE post = posts.id)".to_string()
+            }
+            PostOrderBy::Comments => "json_array_length(comments)".to_string(),
+            PostOrderBy::Random => match self.seed {
+                Some(seed) => format!("seeded_random(posts.id, {seed})"),
+                None => "RANDOM()".to_string(),
+            },
+            // bm25 ranks better matches lower, negate so `desc` is best first
+            PostOrderBy::Relevance if !self.r#match.is_empty() => format!(
+                "-(SELECT rank FROM {SEARCH_SCHEMA}.posts_fts WHERE posts_fts MATCH :match AND rowid = posts.id)"
+            ),
+            PostOrderBy::Relevance => PostSort::Updated.to_string(),
+        };
+        (column, dir)
+    }
+
+    /// Identifies the order a cursor was issued for, `None` if the order
+    /// cannot be paged by cursor.
+    pub fn cursor_order(&self) -> Option<String> {
+        let (_, dir) = self.order();
+        match (self.order_by, self.seed) {
+            (PostOrderBy::Random, None) => None,
+            (PostOrderBy::Random, Some(seed)) => Some(format!("random {dir} {seed}")),
+            (PostOrderBy::Relevance, _) => Some(format!("relevance {dir} {}", self.r#match)),
+            (order_by, _) => Some(format!("{order_by:?} {dir}").to_lowercase()),
+        }
+    }
+
+    /// Cursor positioned at post `id`, i.e. of the page following the one
+    /// ending with it.
+    ///
+    /// Fails with [`rusqlite::Error::QueryReturnedNoRows`] if there is no such
+    /// post, or it has no place in a relevance order.
+    pub fn cursor_at(
+        &self,
+        manager: &PostArchiverManager,
+        id: PostId,
+    ) -> Result<Option<Cursor>, rusqlite::Error> {
+        let Some(order) = self.cursor_order() else {
+            return Ok(None);
+        };
+
+        let (column, _) = self.order();
+        let mut stmt = manager
+            .conn()
+            .prepare_cached(&format!("SELECT {column} FROM posts WHERE posts.id = :id"))?;
+        let value: Option<CursorValue> = if stmt.parameter_index(":match")?.is_some() {
+            stmt.query_row(named_params! { ":id": id, ":match": self.r#match }, |row| {
+                row.get(0)
+            })?
+        } else {
+            stmt.query_row(named_params! { ":id": id }, |row| row.get(0))?
+        };
+        // Posts not matching the `match` expression have no rank
+        let value = value.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+
+        Ok(Some(Cursor {
+            order,
+            value,
+            id: id.raw(),
+        }))
+    }
+
+    /// The posts before and after `id` in this list.
+    fn neighbors(&self, manager: &PostArchiverManager, id: PostId) -> ApiResult<PostNeighbors> {
+        let cursor = match self.cursor_at(manager, id) {
+            Ok(Some(cursor)) => cursor,
+            // Without a stable order there are no neighbors to speak of
+            Ok(None) => return Err(ApiError::bad_request("The order has no neighbors")),
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(ApiError::not_found("Post not found"));
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        use post_archiver::query::Query;
+        let neighbor = |searchs: &SearchQuery| -> ApiResult<Option<PostPreview>> {
+            let posts: Vec<PostPreview> = searchs
+                .build(manager, Some(&cursor))?
+                .pagination(1, 0)
+                .query()?;
+            Ok(posts.into_iter().next())
+        };
+        let next = neighbor(self)?;
+        let prev = neighbor(&self.reversed())?;
+
+        Ok(PostNeighbors { prev, next })
+    }
+
+    /// The same query in the opposite order.
+    fn reversed(&self) -> Self {
+        let dir = match self.dir.unwrap_or(self.order_by.default_dir()) {
+            OrderDir::Asc => OrderDir::Desc,
+            OrderDir::Desc => OrderDir::Asc,
+        };
+        Self {
+            dir: Some(dir),
+            ..self.clone()
+        }
+    }
+}
+
+/// Require posts to be related to any of `ids` in [`MatchMode::Any`], and to
+/// none of `exclude`, through the `(table, column)` relation.
+///
+/// [`MatchMode::All`] is left to the post_archiver query builder.
+fn filter_relation<Q, Id: Serialize>(
+    query: &mut Filtered<Q>,
+    (table, col): (&str, &str),
+    (ids, mode): (&[Id], MatchMode),
+    exclude: &[Id],
+) {
+    let any = if mode == MatchMode::Any { ids } else { &[] };
+    for (ids, op) in [(any, "EXISTS"), (exclude, "NOT EXISTS")] {
+        if ids.is_empty() {
+            continue;
+        }
+        query.filter(
+            format!("{op} (SELECT 1 FROM {table} WHERE post = posts.id AND {col} IN (SELECT value FROM json_each(?)))"),
+            [json_param(ids)],
+        );
+    }
+}
+
+fn json_param<T: Serialize>(ids: &[T]) -> Param {
+    Rc::new(serde_json::to_string(ids).unwrap())
 }
 
 pub async fn list_posts_handler(
     Query(pagination): Query<Pagination>,
     Query(searchs): Query<SearchQuery>,
     State(state): State<AppState>,
-) -> Result<Json<WithRelations<Totalled<Vec<PostPreview>>>>, StatusCode> {
+) -> ApiResult<Json<WithRelations<WithCursor<Totalled<Vec<PostPreview>>>>>> {
+    if searchs.is_full_text() {
+        state.sync_search().await?;
+    }
+
+    let cursor = pagination.cursor(searchs.cursor_order().as_deref())?;
 
-    let manager = state.manager();
+    state
+        .with_manager(move |manager| {
+            let query = searchs
+                .build(manager, cursor.as_ref())?
+                .with_total()
+                .pagination(pagination.limit(), pagination.page());
 
-    let mut query = manager.posts();
+            use post_archiver::query::Query;
+            let mut result: Totalled<Vec<PostPreview>> = query.query()?;
 
-    query.title.contains(&searchs.search);
-    query.authors.extend(searchs.authors.clone());
-    query.tags.extend(searchs.tags.clone());
-    query.collections.extend(searchs.collections.clone());
-    query.platforms.extend(searchs.platforms.clone());
+            searchs.add_snippets(manager.conn(), &mut result.items)?;
 
-    let query = query.with_total().pagination(pagination.limit(), pagination.page());
+            let next_cursor = match result.items.last() {
+                Some(last) if result.items.len() as u64 == pagination.limit() => searchs
+                    .cursor_at(manager, last.id)?
+                    .map(|cursor| cursor.encode()),
+                _ => None,
+            };
 
+            // Cache the total if it was not cached before
+            WithRelations::new(
+                manager,
+                WithCursor {
+                    inner: result,
+                    next_cursor,
+                },
+            )
+            .map_err(ApiError::from)
+            .map(Json::from)
+        })
+        .await
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct PostNeighbors {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub prev: Option<PostPreview>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[ts(optional)]
+    pub next: Option<PostPreview>,
+}
+
+impl RequireRelations for PostNeighbors {
+    fn file_metas(&self) -> Vec<FileMetaId> {
+        self.prev
+            .iter()
+            .chain(self.next.iter())
+            .flat_map(|post| post.file_metas())
+            .collect()
+    }
+}
+
+/// The posts before and after `id` in the list `/posts` would return for the
+/// same query, for browsing through it one post at a time.
+pub async fn post_neighbors_handler(
+    Path(id): Path<PostId>,
+    Query(searchs): Query<SearchQuery>,
+    State(state): State<AppState>,
+) -> ApiResult<Json<WithRelations<PostNeighbors>>> {
+    if !searchs.r#match.is_empty() {
+        state.sync_search().await?;
+    }
+
+    state
+        .with_manager(move |manager| {
+            let neighbors = searchs.neighbors(manager, id)?;
+            WithRelations::new(manager, neighbors)
+                .map_err(ApiError::from)
+                .map(Json::from)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use axum::{http::StatusCode, response::IntoResponse};
     use post_archiver::query::Query;
-    let result = match searchs.order_by {
-        PostOrderBy::Id => query.sort(PostSort::Id, SortDir::Desc).query(),
-        PostOrderBy::Updated => query.sort(PostSort::Updated, SortDir::Desc).query(),
-        PostOrderBy::Random => query.sort_random().query(),
+
+    use super::*;
+    use crate::api::viewer::ViewerDatabase;
+
+    /// Posts 1-4 under tags 1 and 2, author 1 and platform 1, the first three
+    /// updated at the same time:
+    ///
+    /// | post | tags | author | platform | updated    |
+    /// |------|------|--------|----------|------------|
+    /// | 1    | 1    | 1      |          | 2024-01-02 |
+    /// | 2    | 2    | 1      |          | 2024-01-02 |
+    /// | 3    | 1, 2 |        |          | 2024-01-02 |
+    /// | 4    |      |        | 1        | 2024-01-01 |
+    ///
+    /// The viewer database is kept in a temporary directory, returned for
+    /// removal.
+    fn archive(name: &str) -> (PostArchiverManager, PathBuf) {
+        let dir = env::temp_dir().join(format!("viewer-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let manager = PostArchiverManager::open_in_memory().unwrap();
+        ViewerDatabase::open(&dir)
+            .unwrap()
+            .attach(manager.conn())
+            .unwrap();
+        manager
+            .conn()
+            .execute_batch(
+                "
+                INSERT INTO platforms (id, name) VALUES (1, 'site');
+                INSERT INTO posts (id, title, platform, updated) VALUES
+                    (1, 'one', NULL, '2024-01-02 00:00:00'),
+                    (2, 'two', NULL, '2024-01-02 00:00:00'),
+                    (3, 'three', NULL, '2024-01-02 00:00:00'),
+                    (4, 'four', 1, '2024-01-01 00:00:00');
+                INSERT INTO tags (id, name) VALUES (1, 'red'), (2, 'blue');
+                INSERT INTO post_tags (post, tag) VALUES (1, 1), (2, 2), (3, 1), (3, 2);
+                INSERT INTO authors (id, name) VALUES (1, 'someone');
+                INSERT INTO author_posts (author, post) VALUES (1, 1), (1, 2);
+                ",
+            )
+            .unwrap();
+        (manager, dir)
+    }
+
+    fn ids(manager: &PostArchiverManager, searchs: SearchQuery) -> Vec<u32> {
+        let searchs = SearchQuery {
+            order_by: PostOrderBy::Id,
+            dir: Some(OrderDir::Asc),
+            ..searchs
+        };
+        let posts: Vec<PostPreview> = searchs.build(manager, None).unwrap().query().unwrap();
+        posts.into_iter().map(|post| post.id.raw()).collect()
     }
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    // Cache the total if it was not cached before
-    WithRelations::new(&manager, result)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
-        .map(Json::from)
+    #[test]
+    fn tags_match_all_or_any() {
+        let (manager, dir) = archive("posts-tags-any");
+        let tags = vec![TagId::new(1), TagId::new(2)];
+
+        let all = SearchQuery {
+            tags: tags.clone(),
+            ..Default::default()
+        };
+        assert_eq!(ids(&manager, all), [3]);
+
+        let any = SearchQuery {
+            tags,
+            tags_mode: MatchMode::Any,
+            ..Default::default()
+        };
+        assert_eq!(ids(&manager, any), [1, 2, 3]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn exclude_drops_related_posts() {
+        let (manager, dir) = archive("posts-exclude");
+
+        let tags = SearchQuery {
+            exclude_tags: vec![TagId::new(2)],
+            ..Default::default()
+        };
+        assert_eq!(ids(&manager, tags), [1, 4]);
+
+        let authors = SearchQuery {
+            exclude_authors: vec![AuthorId::new(1)],
+            ..Default::default()
+        };
+        assert_eq!(ids(&manager, authors), [3, 4]);
+
+        // Posts without a platform are kept
+        let platforms = SearchQuery {
+            exclude_platforms: vec![PlatformId::new(1)],
+            ..Default::default()
+        };
+        assert_eq!(ids(&manager, platforms), [1, 2, 3]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn any_and_exclude_combine_with_other_filters() {
+        let (manager, dir) = archive("posts-combined");
+
+        let searchs = SearchQuery {
+            tags: vec![TagId::new(1), TagId::new(2)],
+            tags_mode: MatchMode::Any,
+            authors: vec![AuthorId::new(1)],
+            ..Default::default()
+        };
+        assert_eq!(ids(&manager, searchs.clone()), [1, 2]);
+
+        let searchs = SearchQuery {
+            exclude_tags: vec![TagId::new(2)],
+            ..searchs
+        };
+        assert_eq!(ids(&manager, searchs.clone()), [1]);
+
+        let searchs = SearchQuery {
+            search: "two".to_string(),
+            exclude_tags: vec![],
+            ..searchs
+        };
+        assert_eq!(ids(&manager, searchs), [2]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn relevance_is_only_comparable_without_match() {
+        let query = |order_by, r#match: &str| SearchQuery {
+            order_by,
+            r#match: r#match.to_string(),
+            ..Default::default()
+        };
+        assert!(query(PostOrderBy::Updated, "dragon").has_comparable_order());
+        assert!(query(PostOrderBy::Relevance, "").has_comparable_order());
+        assert!(!query(PostOrderBy::Relevance, "dragon").has_comparable_order());
+        assert!(!query(PostOrderBy::Random, "").has_comparable_order());
+    }
+
+    fn neighbors(
+        manager: &PostArchiverManager,
+        searchs: &SearchQuery,
+        id: u32,
+    ) -> (Option<u32>, Option<u32>) {
+        let neighbors = searchs.neighbors(manager, PostId::new(id)).unwrap();
+        let id = |post: Option<PostPreview>| post.map(|post| post.id.raw());
+        (id(neighbors.prev), id(neighbors.next))
+    }
+
+    #[test]
+    fn neighbors_stop_at_the_ends() {
+        let (manager, dir) = archive("posts-neighbors-ends");
+        let searchs = SearchQuery {
+            order_by: PostOrderBy::Id,
+            dir: Some(OrderDir::Asc),
+            ..Default::default()
+        };
+
+        assert_eq!(neighbors(&manager, &searchs, 1), (None, Some(2)));
+        assert_eq!(neighbors(&manager, &searchs, 2), (Some(1), Some(3)));
+        assert_eq!(neighbors(&manager, &searchs, 4), (Some(3), None));
+
+        // Only the posts left by the filters count
+        let searchs = SearchQuery {
+            tags: vec![TagId::new(1)],
+            ..searchs
+        };
+        assert_eq!(neighbors(&manager, &searchs, 3), (Some(1), None));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn neighbors_break_ties_by_id() {
+        let (manager, dir) = archive("posts-neighbors-ties");
+        // Newest first, so 3, 2, 1, then 4
+        let searchs = SearchQuery::default();
+
+        assert_eq!(neighbors(&manager, &searchs, 3), (None, Some(2)));
+        assert_eq!(neighbors(&manager, &searchs, 2), (Some(3), Some(1)));
+        assert_eq!(neighbors(&manager, &searchs, 1), (Some(2), Some(4)));
+        assert_eq!(neighbors(&manager, &searchs, 4), (Some(1), None));
+
+        let searchs = SearchQuery {
+            dir: Some(OrderDir::Asc),
+            ..searchs
+        };
+        assert_eq!(neighbors(&manager, &searchs, 2), (Some(1), Some(3)));
+        assert_eq!(neighbors(&manager, &searchs, 1), (Some(4), Some(2)));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn neighbors_of_unknown_post_are_not_found() {
+        let (manager, dir) = archive("posts-neighbors-unknown");
+
+        let err = SearchQuery::default()
+            .neighbors(&manager, PostId::new(99))
+            .unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::NOT_FOUND);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
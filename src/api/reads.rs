@@ -0,0 +1,142 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    routing::put,
+};
+use chrono::{DateTime, Utc};
+use post_archiver::PostId;
+use rusqlite::{Connection, OptionalExtension, named_params};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use super::{
+    AppState,
+    accounts::User,
+    error::{ApiError, ApiResult},
+    viewer::VIEWER_SCHEMA,
+};
+
+/// How far into a post the reader got, for picking up where they left off.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ReadProgress {
+    /// Index of the content item (e.g. image) in view
+    pub page: Option<u32>,
+    /// Fraction of the post scrolled through, from 0 to 1
+    pub scroll: Option<f64>,
+}
+
+pub fn wrap_reads_route(router: Router<AppState>) -> Router<AppState> {
+    router
+        .route(
+            "/posts/{id}/read",
+            put(mark_read_handler).delete(mark_unread_handler),
+        )
+        .route("/posts/{id}/progress", put(save_progress_handler))
+}
+
+async fn mark_read_handler(
+    Path(id): Path<PostId>,
+    User(user): User,
+    State(state): State<AppState>,
+) -> ApiResult<StatusCode> {
+    require_post(&state, id).await?;
+    state
+        .with_viewer(move |conn| {
+            record_read(conn, user, id)?;
+            Ok(StatusCode::NO_CONTENT)
+        })
+        .await
+}
+
+async fn mark_unread_handler(
+    Path(id): Path<PostId>,
+    User(user): User,
+    State(state): State<AppState>,
+) -> ApiResult<StatusCode> {
+    state
+        .with_viewer(move |conn| {
+            conn.execute(
+                "DELETE FROM post_reads WHERE user = ? AND id = ?",
+                [user, id.raw()],
+            )?;
+            Ok(StatusCode::NO_CONTENT)
+        })
+        .await
+}
+
+/// Save the reading progress, which also marks the post read.
+async fn save_progress_handler(
+    Path(id): Path<PostId>,
+    User(user): User,
+    State(state): State<AppState>,
+    Json(progress): Json<ReadProgress>,
+) -> ApiResult<StatusCode> {
+    if progress
+        .scroll
+        .is_some_and(|scroll| !(0.0..=1.0).contains(&scroll))
+    {
+        return Err(ApiError::bad_request("scroll must be between 0 and 1"));
+    }
+
+    require_post(&state, id).await?;
+    state
+        .with_viewer(move |conn| {
+            conn.execute(
+                "INSERT INTO post_reads (user, id, page, scroll) VALUES (:user, :id, :page, :scroll)
+                ON CONFLICT (user, id) DO UPDATE SET
+                    read_at = CURRENT_TIMESTAMP, page = :page, scroll = :scroll",
+                named_params! {
+                    ":user": user,
+                    ":id": id.raw(),
+                    ":page": progress.page,
+                    ":scroll": progress.scroll,
+                },
+            )?;
+            Ok(StatusCode::NO_CONTENT)
+        })
+        .await
+}
+
+async fn require_post(state: &AppState, id: PostId) -> ApiResult<()> {
+    let exists = state
+        .with_manager(move |manager| Ok(manager.get_post(id)?.is_some()))
+        .await?;
+    if !exists {
+        return Err(ApiError::not_found("Post not found"));
+    }
+    Ok(())
+}
+
+/// Mark `id` read by `user` now, keeping any saved progress.
+pub fn record_read(conn: &Connection, user: u32, id: PostId) -> Result<(), rusqlite::Error> {
+    conn.execute(
+        "INSERT INTO post_reads (user, id) VALUES (?, ?)
+        ON CONFLICT (user, id) DO UPDATE SET read_at = CURRENT_TIMESTAMP",
+        [user, id.raw()],
+    )?;
+    Ok(())
+}
+
+/// When `id` was last read by the current user and how far, checked from an
+/// archive connection.
+pub fn read_state(
+    conn: &Connection,
+    id: PostId,
+) -> Result<Option<(DateTime<Utc>, ReadProgress)>, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT read_at, page, scroll FROM {VIEWER_SCHEMA}.post_reads \
+         WHERE user = viewer_user() AND id = ?"
+    ))?;
+    stmt.query_row([id.raw()], |row| {
+        Ok((
+            row.get(0)?,
+            ReadProgress {
+                page: row.get(1)?,
+                scroll: row.get(2)?,
+            },
+        ))
+    })
+    .optional()
+}
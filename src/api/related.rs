@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+};
+use axum_extra::extract::Query;
+use post_archiver::{
+    PostId,
+    query::{Query as _, Totalled},
+};
+use rusqlite::named_params;
+
+use super::{
+    AppState,
+    error::{ApiError, ApiResult},
+    relation::WithRelations,
+    utils::{Pagination, post_preview::PostPreview},
+};
+
+/// How much sharing one collection, author or tag adds to a post's score.
+/// A shared collection or author says more about a post than a shared tag.
+const COLLECTION_WEIGHT: u32 = 4;
+const AUTHOR_WEIGHT: u32 = 3;
+const TAG_WEIGHT: u32 = 1;
+
+/// Posts sharing the most collections, authors and tags with post `id`, best
+/// first and paged by `page`.
+pub async fn related_posts_handler(
+    Path(id): Path<PostId>,
+    Query(pagination): Query<Pagination>,
+    State(state): State<AppState>,
+) -> ApiResult<Json<WithRelations<Totalled<Vec<PostPreview>>>>> {
+    state
+        .with_manager(move |manager| {
+            if manager
+                .get_post(id)?
+                .is_none()
+            {
+                return Err(ApiError::not_found("Post not found"));
+            }
+
+            // Every post sharing something, with how much it shares
+            let scored = format!(
+                "
+                SELECT post, SUM(weight) AS score FROM (
+                    SELECT post, {COLLECTION_WEIGHT} AS weight FROM collection_posts
+                    WHERE collection IN (SELECT collection FROM collection_posts WHERE post = :id)
+                    UNION ALL
+                    SELECT post, {AUTHOR_WEIGHT} FROM author_posts
+                    WHERE author IN (SELECT author FROM author_posts WHERE post = :id)
+                    UNION ALL
+                    SELECT post, {TAG_WEIGHT} FROM post_tags
+                    WHERE tag IN (SELECT tag FROM post_tags WHERE post = :id)
+                )
+                WHERE post != :id
+                GROUP BY post
+                "
+            );
+
+            let total: u64 = manager
+                .conn()
+                .prepare_cached(&format!("SELECT COUNT() FROM ({scored})"))?
+                .query_row(named_params! { ":id": id }, |row| row.get(0))?;
+
+            let [(_, limit), (_, offset)] = pagination.params();
+            let mut stmt = manager.conn().prepare_cached(&format!(
+                "SELECT post FROM ({scored}) ORDER BY score DESC, post DESC LIMIT :limit OFFSET :offset"
+            ))?;
+            let ids = stmt
+                .query_map(
+                    named_params! { ":id": id, ":limit": limit, ":offset": offset },
+                    |row| row.get::<_, PostId>(0),
+                )
+                .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())?;
+
+            let mut posts: Vec<PostPreview> = if ids.is_empty() {
+                vec![]
+            } else {
+                let mut query = manager.posts();
+                query.ids.extend(ids.iter().cloned());
+                query
+                    .query()?
+            };
+
+            let rank: HashMap<PostId, usize> = ids.into_iter().enumerate().map(|(i, id)| (id, i)).collect();
+            posts.sort_by_key(|post| rank[&post.id]);
+
+            WithRelations::new(
+                manager,
+                Totalled {
+                    items: posts,
+                    total,
+                },
+            )
+            .map_err(ApiError::from)
+            .map(Json::from)
+        })
+        .await
+}
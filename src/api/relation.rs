@@ -1,13 +1,14 @@
 use std::{collections::HashSet, fmt::Debug, hash::Hash};
 
 use post_archiver::{
-    Author, AuthorId, Collection, CollectionId, FileMeta, FileMetaId, Platform, PlatformId, Tag, TagId, manager::PostArchiverManager, query::FromQuery, utils::AsTable
+    Author, AuthorId, Collection, CollectionId, FileMeta, FileMetaId, Platform, PlatformId, Post,
+    PostId, Tag, TagId, manager::PostArchiverManager, query::FromQuery, utils::AsTable,
 };
 use rusqlite::Connection;
 use serde::Serialize;
 use ts_rs::TS;
 
-use super::category::Category;
+use super::{category::Category, utils::post_preview::PostPreview};
 
 #[derive(Debug, Serialize, TS)]
 #[ts(export)]
@@ -107,6 +108,11 @@ pub trait RelationTarget: Serialize + Sized {
 
     fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error>;
 
+    /// `SELECT` whose rows [`RelationTarget::from_row`] reads.
+    fn select_sql() -> String {
+        format!("SELECT * FROM {}", Self::TABLE_NAME)
+    }
+
     fn query(
         conn: &Connection,
         ids: impl IntoIterator<Item = Self::Id>,
@@ -117,8 +123,8 @@ pub trait RelationTarget: Serialize + Sized {
         }
 
         let mut stmt = conn.prepare_cached(&format!(
-            "SELECT * FROM {} WHERE id IN (SELECT value FROM json_each(?))",
-            Self::TABLE_NAME
+            "{} WHERE id IN (SELECT value FROM json_each(?))",
+            Self::select_sql()
         ))?;
 
         let rows = stmt.query_map([serde_json::to_string(&ids).unwrap()], |row| {
@@ -138,6 +144,19 @@ impl<T: Category> RelationTarget for T {
     }
 }
 
+impl RelationTarget for PostPreview {
+    type Id = PostId;
+    const TABLE_NAME: &'static str = <Post as AsTable>::TABLE_NAME;
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        <PostPreview as FromQuery>::from_row(row)
+    }
+
+    fn select_sql() -> String {
+        <PostPreview as FromQuery>::select_sql()
+    }
+}
+
 impl RelationTarget for FileMeta {
     type Id = FileMetaId;
     const TABLE_NAME: &'static str = <FileMeta as AsTable>::TABLE_NAME;
@@ -0,0 +1,127 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::{StatusCode, Uri},
+    routing::{delete, get},
+};
+use axum_extra::extract::Query;
+use chrono::{DateTime, Utc};
+use rusqlite::{ErrorCode, Row};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use super::{
+    AppState,
+    accounts::User,
+    error::{ApiError, ApiResult},
+    posts::SearchQuery,
+};
+
+/// A `/posts` query kept under a name, to run again later.
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct SavedSearch {
+    pub id: u32,
+    pub name: String,
+    /// Query string of `/posts`, e.g. `tags=1&order_by=title`
+    pub query: String,
+    pub saved: DateTime<Utc>,
+}
+
+impl SavedSearch {
+    fn from_row(row: &Row) -> Result<Self, rusqlite::Error> {
+        Ok(Self {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            query: row.get("query")?,
+            saved: row.get("saved")?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewSavedSearch {
+    name: String,
+    query: String,
+}
+
+pub fn wrap_saved_searches_route(router: Router<AppState>) -> Router<AppState> {
+    router
+        .route(
+            "/searches",
+            get(list_saved_searches_handler).post(save_search_handler),
+        )
+        .route("/searches/{id}", delete(delete_saved_search_handler))
+}
+
+/// The user's saved searches, by name.
+async fn list_saved_searches_handler(
+    User(user): User,
+    State(state): State<AppState>,
+) -> ApiResult<Json<Vec<SavedSearch>>> {
+    state
+        .with_viewer(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT * FROM saved_searches WHERE user = ? ORDER BY name COLLATE NOCASE, id",
+            )?;
+            let searches = stmt
+                .query_map([user], SavedSearch::from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Json(searches))
+        })
+        .await
+}
+
+async fn save_search_handler(
+    User(user): User,
+    State(state): State<AppState>,
+    Json(search): Json<NewSavedSearch>,
+) -> ApiResult<(StatusCode, Json<SavedSearch>)> {
+    let name = search.name.trim().to_string();
+    if name.is_empty() {
+        return Err(ApiError::bad_request("Name must not be empty"));
+    }
+    let query = search.query.trim_start_matches('?').to_string();
+    let uri: Uri = format!("/posts?{query}")
+        .parse()
+        .map_err(|_| ApiError::bad_request("Invalid query"))?;
+    Query::<SearchQuery>::try_from_uri(&uri)
+        .map_err(|err| ApiError::bad_request("Invalid query").with_source(err))?;
+
+    state
+        .with_viewer(move |conn| {
+            let inserted = conn.query_row(
+                "INSERT INTO saved_searches (user, name, query) VALUES (?, ?, ?) RETURNING *",
+                (user, &name, &query),
+                SavedSearch::from_row,
+            );
+            match inserted {
+                Err(rusqlite::Error::SqliteFailure(err, _))
+                    if err.code == ErrorCode::ConstraintViolation =>
+                {
+                    Err(ApiError::new(StatusCode::CONFLICT, "Name already taken"))
+                }
+                result => Ok((StatusCode::CREATED, Json(result?))),
+            }
+        })
+        .await
+}
+
+async fn delete_saved_search_handler(
+    Path(id): Path<u32>,
+    User(user): User,
+    State(state): State<AppState>,
+) -> ApiResult<StatusCode> {
+    state
+        .with_viewer(move |conn| {
+            let deleted = conn.execute(
+                "DELETE FROM saved_searches WHERE user = ? AND id = ?",
+                [user, id],
+            )?;
+            if deleted == 0 {
+                return Err(ApiError::not_found("Saved search not found"));
+            }
+            Ok(StatusCode::NO_CONTENT)
+        })
+        .await
+}
@@ -0,0 +1,291 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use post_archiver::utils::DATABASE_NAME;
+use rusqlite::{Connection, OptionalExtension, named_params};
+use tracing::info;
+
+/// Database of the search index, next to the viewer database but separate
+/// from it, so indexing never holds up writes to accounts, favorites and
+/// history.
+pub const SEARCH_DATABASE_NAME: &str = "post-archiver-viewer-search.db";
+
+/// Schema name the search index is attached under on archive connections.
+pub const SEARCH_SCHEMA: &str = "search";
+
+/// Longest the index may lag behind the archive when no change was noticed,
+/// e.g. because the archive cannot be watched.
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// FTS5 index over post titles and the text parts of their content, kept in
+/// its own database in the viewer's data directory.
+///
+/// The index is keyed by post id (`rowid`) and refreshed incrementally from
+/// `posts.updated` and `posts.id`, so only posts touched by the archiver since
+/// the last sync are re-indexed.
+#[derive(Debug)]
+pub struct SearchIndex {
+    conn: Mutex<Connection>,
+    path: PathBuf,
+    /// Set when the archive changed since the last sync
+    stale: AtomicBool,
+    synced: Mutex<Option<Instant>>,
+}
+
+impl SearchIndex {
+    /// Open the index in `dir`, creating it if needed, for the archive at
+    /// `archive`.
+    pub fn open(dir: &Path, archive: &Path) -> Result<Self, rusqlite::Error> {
+        let path = dir.join(SEARCH_DATABASE_NAME);
+        let conn = Connection::open(&path)?;
+
+        conn.execute_batch(
+            "
+            PRAGMA journal_mode = WAL;   -- let archive connections read while we index
+            PRAGMA busy_timeout = 5000;
+
+            CREATE TABLE IF NOT EXISTS search_meta (
+                key TEXT NOT NULL PRIMARY KEY,
+                value
+            );
+            ",
+        )?;
+
+        let exists = conn
+            .prepare("SELECT 1 FROM sqlite_master WHERE name = 'posts_fts'")?
+            .exists([])?;
+        if !exists {
+            conn.execute_batch(
+                "
+                CREATE VIRTUAL TABLE posts_fts USING fts5(
+                    title,
+                    content,
+                    tokenize = 'unicode61 remove_diacritics 2'
+                );
+                -- Rank title hits above body hits
+                INSERT INTO posts_fts (posts_fts, rank) VALUES ('rank', 'bm25(10.0, 1.0)');
+                ",
+            )?;
+        }
+
+        // Never written to, only read from while indexing
+        conn.execute(
+            "ATTACH DATABASE ? AS archive",
+            [archive.join(DATABASE_NAME).to_string_lossy()],
+        )?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            path,
+            stale: AtomicBool::new(true),
+            synced: Mutex::new(None),
+        })
+    }
+
+    /// Attach the index to an archive connection as [`SEARCH_SCHEMA`], so
+    /// queries can match against it.
+    pub fn attach(&self, conn: &Connection) -> Result<(), rusqlite::Error> {
+        conn.execute(
+            &format!("ATTACH DATABASE ? AS {SEARCH_SCHEMA}"),
+            [self.path.to_string_lossy()],
+        )?;
+        Ok(())
+    }
+
+    /// Note that the archive changed, so the next [`SearchIndex::sync_if_due`]
+    /// syncs.
+    pub fn mark_stale(&self) {
+        self.stale.store(true, Ordering::Relaxed);
+    }
+
+    /// Sync if the archive changed, or if it has not been looked at for
+    /// [`SYNC_INTERVAL`], rather than on every full-text query.
+    pub fn sync_if_due(&self) -> Result<(), rusqlite::Error> {
+        let stale = self.stale.swap(false, Ordering::Relaxed);
+        let expired = self
+            .synced
+            .lock()
+            .unwrap()
+            .is_none_or(|synced| synced.elapsed() >= SYNC_INTERVAL);
+        if !stale && !expired {
+            return Ok(());
+        }
+
+        self.sync().inspect_err(|_| self.mark_stale())
+    }
+
+    /// Bring the index up to date with the archive.
+    ///
+    /// Posts updated or added since the last sync are re-indexed. Whenever
+    /// anything changed, posts no longer in the archive are dropped, so a
+    /// deletion is noticed even when an insert kept the count the same.
+    pub fn sync(&self) -> Result<(), rusqlite::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let (latest, max_id, count): (Option<String>, Option<u32>, u64) = tx.query_row(
+            "SELECT MAX(updated), MAX(id), COUNT() FROM archive.posts",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+
+        let get_meta = |key: &str| {
+            // NULL while the archive is empty
+            tx.query_row(
+                "SELECT value FROM search_meta WHERE key = ?",
+                [key],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()
+            .map(Option::flatten)
+        };
+        let indexed = get_meta("updated")?;
+        let indexed_id = get_meta("id")?.and_then(|id| id.parse::<u32>().ok());
+        let indexed_count = get_meta("count")?.and_then(|count| count.parse::<u64>().ok());
+
+        if latest == indexed && max_id == indexed_id && indexed_count == Some(count) {
+            drop(tx);
+            *self.synced.lock().unwrap() = Some(Instant::now());
+            return Ok(());
+        }
+
+        if indexed.is_none() {
+            info!("Building full-text search index for {count} posts");
+        }
+
+        let since = indexed.unwrap_or_default();
+        let since_id = indexed_id.unwrap_or(0);
+        tx.execute(
+            "DELETE FROM posts_fts WHERE rowid IN (SELECT id FROM archive.posts WHERE updated > :since OR id > :since_id)",
+            named_params! { ":since": since, ":since_id": since_id },
+        )?;
+        let indexed_posts = tx.execute(
+            "
+            INSERT INTO posts_fts (rowid, title, content)
+            SELECT
+                id,
+                title,
+                (SELECT group_concat(value, char(10)) FROM json_each(posts.content) WHERE type = 'text')
+            FROM archive.posts WHERE updated > :since OR id > :since_id
+            ",
+            named_params! { ":since": since, ":since_id": since_id },
+        )?;
+
+        let removed = tx.execute(
+            "DELETE FROM posts_fts WHERE rowid NOT IN (SELECT id FROM archive.posts)",
+            [],
+        )?;
+
+        tx.execute(
+            "INSERT OR REPLACE INTO search_meta (key, value) VALUES ('updated', ?), ('id', ?), ('count', ?)",
+            (&latest, max_id.map(|id| id.to_string()), count.to_string()),
+        )?;
+        tx.commit()?;
+        *self.synced.lock().unwrap() = Some(Instant::now());
+
+        info!("Full-text search index synced ({indexed_posts} indexed, {removed} removed)");
+        Ok(())
+    }
+
+    /// Check that `expr` is a valid FTS5 query, so syntax errors can be
+    /// reported as a bad request rather than a failed query.
+    pub fn check_match(conn: &Connection, expr: &str) -> Result<(), rusqlite::Error> {
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT 1 FROM {SEARCH_SCHEMA}.posts_fts WHERE posts_fts MATCH ? LIMIT 1"
+        ))?;
+        stmt.exists([expr])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("viewer-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn indexed(index: &SearchIndex) -> Vec<u32> {
+        let conn = index.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT rowid FROM posts_fts ORDER BY rowid")
+            .unwrap();
+        stmt.query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn sync_notices_delete_and_insert() {
+        let dir = temp_dir("search-sync");
+        let archive = Connection::open(dir.join(DATABASE_NAME)).unwrap();
+        archive
+            .execute_batch(
+                "
+                CREATE TABLE posts (id INTEGER PRIMARY KEY, title TEXT, content TEXT, updated TEXT);
+                INSERT INTO posts VALUES (1, 'one', '[]', '2024-01-02'), (2, 'two', '[]', '2024-01-03');
+                ",
+            )
+            .unwrap();
+
+        let index = SearchIndex::open(&dir, &dir).unwrap();
+        index.sync().unwrap();
+        assert_eq!(indexed(&index), [1, 2]);
+
+        // Same count and an older timestamp, only the id gives the new post away
+        archive
+            .execute_batch(
+                "
+                DELETE FROM posts WHERE id = 2;
+                INSERT INTO posts VALUES (3, 'three', '[]', '2024-01-01');
+                ",
+            )
+            .unwrap();
+        index.sync().unwrap();
+        assert_eq!(indexed(&index), [1, 3]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sync_if_due_skips_until_stale() {
+        let dir = temp_dir("search-due");
+        let archive = Connection::open(dir.join(DATABASE_NAME)).unwrap();
+        archive
+            .execute_batch(
+                "CREATE TABLE posts (id INTEGER PRIMARY KEY, title TEXT, content TEXT, updated TEXT);",
+            )
+            .unwrap();
+
+        let index = SearchIndex::open(&dir, &dir).unwrap();
+        index.sync_if_due().unwrap();
+
+        archive
+            .execute(
+                "INSERT INTO posts VALUES (1, 'one', '[]', '2024-01-01')",
+                [],
+            )
+            .unwrap();
+        index.sync_if_due().unwrap();
+        assert!(indexed(&index).is_empty());
+
+        index.mark_stale();
+        index.sync_if_due().unwrap();
+        assert_eq!(indexed(&index), [1]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
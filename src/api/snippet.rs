@@ -0,0 +1,296 @@
+use std::{collections::HashMap, ops::Range};
+
+use post_archiver::{Content, PostId};
+use rusqlite::Connection;
+use serde::Serialize;
+use ts_rs::TS;
+
+use super::search::SEARCH_SCHEMA;
+
+/// Characters of context kept on each side of the first match in the content.
+const CONTEXT_CHARS: usize = 40;
+/// Tokens of context FTS5 keeps in a content snippet.
+const CONTEXT_TOKENS: usize = 24;
+
+// Control characters never appear in archived text, so they are safe to use
+// as highlight markers when asking FTS5 for snippets.
+const MARK_START: char = '\u{2}';
+const MARK_END: char = '\u{3}';
+
+/// Why a post matched a search, as runs of plain and highlighted text.
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct Snippet {
+    /// The whole title, or empty if the title did not match
+    pub title: Vec<Fragment>,
+    /// An excerpt of the text content, or empty if the content did not match
+    pub content: Vec<Fragment>,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct Fragment {
+    pub text: String,
+    pub highlight: bool,
+}
+
+impl Snippet {
+    /// Snippets for posts matched by a full-text `match` query, highlighted
+    /// by FTS5 itself so they agree with its tokenization.
+    pub fn full_text(
+        conn: &Connection,
+        expr: &str,
+        ids: impl IntoIterator<Item = PostId>,
+    ) -> Result<HashMap<PostId, Snippet>, rusqlite::Error> {
+        let ids: Vec<PostId> = ids.into_iter().collect();
+        let mut stmt = conn.prepare_cached(&format!(
+            "
+            SELECT
+                rowid,
+                highlight(posts_fts, 0, char(2), char(3)),
+                snippet(posts_fts, 1, char(2), char(3), '…', {CONTEXT_TOKENS})
+            FROM {SEARCH_SCHEMA}.posts_fts
+            WHERE posts_fts MATCH ? AND rowid IN (SELECT value FROM json_each(?))
+            "
+        ))?;
+
+        let rows = stmt.query_map((expr, serde_json::to_string(&ids).unwrap()), |row| {
+            let title: String = row.get(1)?;
+            let content: Option<String> = row.get(2)?;
+            let snippet = Snippet {
+                title: Fragment::from_marked(&title),
+                content: content
+                    .as_deref()
+                    .map(Fragment::from_marked)
+                    .unwrap_or_default(),
+            };
+            Ok((row.get(0)?, snippet))
+        })?;
+
+        rows.collect()
+    }
+
+    /// Snippets for posts matched by a plain `search` term, compared
+    /// case-insensitively like the `LIKE` filter that found them.
+    pub fn plain(
+        conn: &Connection,
+        term: &str,
+        ids: impl IntoIterator<Item = PostId>,
+    ) -> Result<HashMap<PostId, Snippet>, rusqlite::Error> {
+        let ids: Vec<PostId> = ids.into_iter().collect();
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, title, content FROM posts WHERE id IN (SELECT value FROM json_each(?))",
+        )?;
+
+        let rows = stmt.query_map([serde_json::to_string(&ids).unwrap()], |row| {
+            let title: String = row.get(1)?;
+            let content: String = row.get(2)?;
+            let content: Vec<Content> = serde_json::from_str(&content).unwrap_or_default();
+
+            let title_matches = find_all(&title, term);
+            let title = if title_matches.is_empty() {
+                vec![]
+            } else {
+                Fragment::from_ranges(&title, 0..title.len(), &title_matches)
+            };
+
+            let content = content
+                .iter()
+                .find_map(|content| match content {
+                    Content::Text(text) => {
+                        let matches = find_all(text, term);
+                        let first = matches.first()?.clone();
+                        Some(Fragment::excerpt(text, first, &matches))
+                    }
+                    Content::File(_) => None,
+                })
+                .unwrap_or_default();
+
+            Ok((row.get(0)?, Snippet { title, content }))
+        })?;
+
+        rows.collect()
+    }
+}
+
+impl Fragment {
+    fn new(text: &str, highlight: bool) -> Self {
+        Self {
+            text: text.to_string(),
+            highlight,
+        }
+    }
+
+    /// Split text delimited by [`MARK_START`]/[`MARK_END`] into fragments.
+    /// Returns nothing if no part of it is highlighted.
+    fn from_marked(text: &str) -> Vec<Fragment> {
+        let mut fragments = Vec::new();
+        let mut rest = text;
+        while let Some(start) = rest.find(MARK_START) {
+            let end = rest[start..]
+                .find(MARK_END)
+                .map_or(rest.len(), |end| start + end);
+            if start > 0 {
+                fragments.push(Fragment::new(&rest[..start], false));
+            }
+            fragments.push(Fragment::new(&rest[start + 1..end], true));
+            rest = rest.get(end + 1..).unwrap_or_default();
+        }
+
+        if fragments.is_empty() {
+            return fragments;
+        }
+        if !rest.is_empty() {
+            fragments.push(Fragment::new(rest, false));
+        }
+        fragments
+    }
+
+    /// Fragments for `text[window]`, highlighting the `matches` inside it.
+    fn from_ranges(text: &str, window: Range<usize>, matches: &[Range<usize>]) -> Vec<Fragment> {
+        let mut fragments = Vec::new();
+        let mut cursor = window.start;
+        for range in matches {
+            if range.start < cursor || range.end > window.end {
+                continue;
+            }
+            if range.start > cursor {
+                fragments.push(Fragment::new(&text[cursor..range.start], false));
+            }
+            fragments.push(Fragment::new(&text[range.clone()], true));
+            cursor = range.end;
+        }
+        if cursor < window.end {
+            fragments.push(Fragment::new(&text[cursor..window.end], false));
+        }
+        fragments
+    }
+
+    /// Fragments for the text around `first`, with ellipses where it was cut.
+    fn excerpt(text: &str, first: Range<usize>, matches: &[Range<usize>]) -> Vec<Fragment> {
+        let start = text[..first.start]
+            .char_indices()
+            .rev()
+            .nth(CONTEXT_CHARS - 1)
+            .map_or(0, |(i, _)| i);
+        let end = text[first.end..]
+            .char_indices()
+            .nth(CONTEXT_CHARS)
+            .map_or(text.len(), |(i, _)| first.end + i);
+
+        let mut fragments = Self::from_ranges(text, start..end, matches);
+        if start > 0 {
+            fragments.insert(0, Fragment::new("…", false));
+        }
+        if end < text.len() {
+            fragments.push(Fragment::new("…", false));
+        }
+        fragments
+    }
+}
+
+/// Byte ranges of every non-overlapping, case-insensitive occurrence of `term`.
+fn find_all(text: &str, term: &str) -> Vec<Range<usize>> {
+    let mut matches = Vec::new();
+    if term.is_empty() {
+        return matches;
+    }
+
+    let mut start = 0;
+    while start < text.len() {
+        let rest = &text[start..];
+        let mut haystack = rest.char_indices();
+        let mut needle = term.chars();
+        let end = loop {
+            let Some(expected) = needle.next() else {
+                break Some(haystack.next().map_or(rest.len(), |(i, _)| i));
+            };
+            match haystack.next() {
+                Some((_, c)) if c.to_lowercase().eq(expected.to_lowercase()) => {}
+                _ => break None,
+            }
+        };
+
+        match end {
+            Some(end) => {
+                matches.push(start..start + end);
+                start += end;
+            }
+            None => start += rest.chars().next().map_or(1, char::len_utf8),
+        }
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fragments written out with highlights in brackets.
+    fn render(fragments: &[Fragment]) -> String {
+        fragments
+            .iter()
+            .map(|fragment| match fragment.highlight {
+                true => format!("[{}]", fragment.text),
+                false => fragment.text.clone(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn from_marked_splits_highlights() {
+        let marked = format!("a {MARK_START}blue{MARK_END} sky, {MARK_START}blue{MARK_END}");
+        assert_eq!(
+            render(&Fragment::from_marked(&marked)),
+            "a [blue] sky, [blue]"
+        );
+    }
+
+    #[test]
+    fn from_marked_without_highlight_is_empty() {
+        assert!(Fragment::from_marked("nothing here").is_empty());
+    }
+
+    #[test]
+    fn from_marked_tolerates_missing_end() {
+        let marked = format!("a {MARK_START}blue");
+        assert_eq!(render(&Fragment::from_marked(&marked)), "a [blue]");
+    }
+
+    #[test]
+    fn find_all_ignores_case() {
+        assert_eq!(find_all("Dragon and dRaGoN", "dragon"), [0..6, 11..17]);
+        assert!(find_all("dragon", "").is_empty());
+    }
+
+    #[test]
+    fn find_all_handles_multibyte_text() {
+        let text = "日本の龍と龍";
+        let matches = find_all(text, "龍");
+        assert_eq!(matches.len(), 2);
+        assert!(matches.iter().all(|range| &text[range.clone()] == "龍"));
+    }
+
+    #[test]
+    fn excerpt_cuts_with_ellipses() {
+        let text = format!("{}dragon{}", "x".repeat(100), "y".repeat(100));
+        let matches = find_all(&text, "dragon");
+        let fragments = Fragment::excerpt(&text, matches[0].clone(), &matches);
+        assert_eq!(
+            render(&fragments),
+            format!(
+                "…{}[dragon]{}…",
+                "x".repeat(CONTEXT_CHARS),
+                "y".repeat(CONTEXT_CHARS)
+            )
+        );
+    }
+
+    #[test]
+    fn excerpt_keeps_short_text_whole() {
+        let text = "a dragon flies";
+        let matches = find_all(text, "dragon");
+        let fragments = Fragment::excerpt(text, matches[0].clone(), &matches);
+        assert_eq!(render(&fragments), "a [dragon] flies");
+    }
+}
@@ -1,9 +1,9 @@
-use axum::{Json, extract::State, http::StatusCode};
+use axum::{Json, extract::State};
 use post_archiver::utils::VERSION;
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
-use super::AppState;
+use super::{AppState, error::ApiResult};
 
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[serde(rename_all = "camelCase")]
@@ -19,50 +19,47 @@ pub struct SummaryResponse {
     files: u32,
 }
 
-pub async fn get_summary_api(
-    State(state): State<AppState>,
-) -> Result<Json<SummaryResponse>, StatusCode> {
-    let manager = state.manager();
-    let conn = manager.conn();
+pub async fn get_summary_api(State(state): State<AppState>) -> ApiResult<Json<SummaryResponse>> {
+    summary(&state).await.map(Json)
+}
 
-    let post_archiver_version: String = conn
-        .query_row("SELECT version FROM post_archiver_meta", [], |row| {
-            row.get(0)
-        })
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+/// Version and row counts of the archive behind `state`.
+pub async fn summary(state: &AppState) -> ApiResult<SummaryResponse> {
+    state
+        .with_manager(move |manager| {
+            let conn = manager.conn();
 
-    let platforms: u32 = conn
-        .query_row("SELECT COUNT() FROM platforms", [], |row| row.get(0))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let post_archiver_version: String =
+                conn.query_row("SELECT version FROM post_archiver_meta", [], |row| {
+                    row.get(0)
+                })?;
 
-    let tags: u32 = conn
-        .query_row("SELECT COUNT() FROM tags", [], |row| row.get(0))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let platforms: u32 =
+                conn.query_row("SELECT COUNT() FROM platforms", [], |row| row.get(0))?;
 
-    let authors: u32 = conn
-        .query_row("SELECT COUNT() FROM authors", [], |row| row.get(0))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let tags: u32 = conn.query_row("SELECT COUNT() FROM tags", [], |row| row.get(0))?;
 
-    let collections: u32 = conn
-        .query_row("SELECT COUNT() FROM collections", [], |row| row.get(0))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let authors: u32 =
+                conn.query_row("SELECT COUNT() FROM authors", [], |row| row.get(0))?;
 
-    let posts: u32 = conn
-        .query_row("SELECT COUNT() FROM posts", [], |row| row.get(0))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let collections: u32 =
+                conn.query_row("SELECT COUNT() FROM collections", [], |row| row.get(0))?;
 
-    let files: u32 = conn
-        .query_row("SELECT COUNT() FROM file_metas", [], |row| row.get(0))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let posts: u32 = conn.query_row("SELECT COUNT() FROM posts", [], |row| row.get(0))?;
 
-    Ok(Json(SummaryResponse {
-        version: VERSION.to_string(),
-        post_archiver_version,
-        platforms,
-        collections,
-        authors,
-        tags,
-        posts,
-        files,
-    }))
+            let files: u32 =
+                conn.query_row("SELECT COUNT() FROM file_metas", [], |row| row.get(0))?;
+
+            Ok(SummaryResponse {
+                version: VERSION.to_string(),
+                post_archiver_version,
+                platforms,
+                collections,
+                authors,
+                tags,
+                posts,
+                files,
+            })
+        })
+        .await
 }
@@ -1,9 +1,15 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use cursor::Cursor;
 use serde::{Deserialize, Serialize};
 
+use super::error::{ApiError, ApiResult};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Pagination {
     pub limit: Option<u64>,
     pub page: Option<u64>,
+    /// `next_cursor` of the previous page, takes precedence over `page`
+    pub cursor: Option<String>,
 }
 
 impl Pagination {
@@ -11,8 +17,24 @@ impl Pagination {
         self.limit.unwrap_or(20)
     }
     pub fn page(&self) -> u64 {
+        // A cursor already marks where the page starts
+        if self.cursor.is_some() {
+            return 0;
+        }
         self.page.unwrap_or(0)
     }
+    /// Decode the cursor, checking it was issued for the same order.
+    ///
+    /// `order` is `None` for orders that cannot be paged by cursor.
+    pub fn cursor(&self, order: Option<&str>) -> ApiResult<Option<Cursor>> {
+        let Some(cursor) = &self.cursor else {
+            return Ok(None);
+        };
+        Cursor::decode(cursor)
+            .filter(|cursor| Some(cursor.order.as_str()) == order)
+            .map(Some)
+            .ok_or_else(|| ApiError::bad_request("Invalid cursor for this order"))
+    }
     pub fn params(&self) -> [(&'static str, u64); 2] {
         let limit = self.limit();
         let page = self.page() * limit;
@@ -20,13 +42,151 @@ impl Pagination {
     }
 }
 
+/// A time bound given as a query param, either RFC 3339 or a date-only
+/// `YYYY-MM-DD` covering that whole day (UTC).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TimeBound {
+    At(DateTime<Utc>),
+    Day(NaiveDate),
+}
+
+impl TimeBound {
+    /// The earliest instant within the bound, for `*_after` params.
+    pub fn start(self) -> DateTime<Utc> {
+        match self {
+            TimeBound::At(at) => at,
+            TimeBound::Day(day) => day.and_time(NaiveTime::MIN).and_utc(),
+        }
+    }
+
+    /// The latest instant within the bound, for `*_before` params.
+    pub fn end(self) -> DateTime<Utc> {
+        match self {
+            TimeBound::At(at) => at,
+            TimeBound::Day(day) => day
+                .and_hms_nano_opt(23, 59, 59, 999_999_999)
+                .unwrap()
+                .and_utc(),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeBound {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Ok(at) = DateTime::parse_from_rfc3339(&value) {
+            return Ok(TimeBound::At(at.to_utc()));
+        }
+        NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+            .map(TimeBound::Day)
+            .map_err(|_| format!("invalid time `{value}`, expected RFC 3339 or YYYY-MM-DD"))
+    }
+}
+
+impl From<TimeBound> for String {
+    fn from(value: TimeBound) -> Self {
+        match value {
+            TimeBound::At(at) => at.to_rfc3339(),
+            TimeBound::Day(day) => day.to_string(),
+        }
+    }
+}
+
+pub mod cursor {
+    use std::fmt::Display;
+
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+    use serde::{Deserialize, Serialize};
+
+    /// Keyset pagination position: the sort key and id of the last item of a
+    /// page. Unlike an offset it stays put when items are inserted meanwhile.
+    ///
+    /// Sent to clients as an opaque string.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct Cursor {
+        /// The order the cursor was issued for
+        pub order: String,
+        pub value: CursorValue,
+        pub id: u32,
+    }
+
+    impl Cursor {
+        pub fn encode(&self) -> String {
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+        }
+
+        pub fn decode(cursor: &str) -> Option<Self> {
+            let json = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+            serde_json::from_slice(&json).ok()
+        }
+
+        /// `WHERE` clause selecting rows after the cursor, for rows ordered
+        /// by `column` then `id`, both in `dir`.
+        ///
+        /// Binds `:cursor_value` then `:cursor_id`.
+        pub fn seek_clause(column: &str, id: &str, dir: &str) -> String {
+            let op = if dir.eq_ignore_ascii_case("asc") {
+                ">"
+            } else {
+                "<"
+            };
+            format!("({column}, {id}) {op} (:cursor_value, :cursor_id)")
+        }
+    }
+
+    /// A sort key as stored by SQLite.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(untagged)]
+    pub enum CursorValue {
+        Integer(i64),
+        Real(f64),
+        Text(String),
+    }
+
+    impl FromSql for CursorValue {
+        fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+            match value {
+                ValueRef::Integer(value) => Ok(CursorValue::Integer(value)),
+                ValueRef::Real(value) => Ok(CursorValue::Real(value)),
+                ValueRef::Text(_) => value
+                    .as_str()
+                    .map(|text| CursorValue::Text(text.to_string())),
+                ValueRef::Null | ValueRef::Blob(_) => Err(FromSqlError::InvalidType),
+            }
+        }
+    }
+
+    impl ToSql for CursorValue {
+        fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+            match self {
+                CursorValue::Integer(value) => value.to_sql(),
+                CursorValue::Real(value) => value.to_sql(),
+                CursorValue::Text(value) => value.to_sql(),
+            }
+        }
+    }
+
+    impl Display for CursorValue {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                CursorValue::Integer(value) => value.fmt(f),
+                CursorValue::Real(value) => value.fmt(f),
+                CursorValue::Text(value) => value.fmt(f),
+            }
+        }
+    }
+}
+
 pub mod post_preview {
     use chrono::{DateTime, Utc};
-    use post_archiver::{FileMetaId, Post, PostId, impl_from_query};
+    use post_archiver::{FileMetaId, Post, PostId, query::FromQuery};
     use serde::Serialize;
     use ts_rs::TS;
 
-    use crate::api::relation::RequireRelations;
+    use crate::api::{relation::RequireRelations, snippet::Snippet, viewer::VIEWER_SCHEMA};
 
     #[derive(Debug, Clone, Serialize, TS)]
     #[ts(export)]
@@ -35,14 +195,44 @@ pub mod post_preview {
         pub title: String,
         pub thumb: Option<FileMetaId>,
         pub updated: DateTime<Utc>,
+        /// When the post was last opened, `null` if never
+        pub read_at: Option<DateTime<Utc>>,
+        /// Only present when listing posts with a search term
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[ts(optional)]
+        pub snippet: Option<Snippet>,
     }
 
-    impl_from_query! {
-        PostPreview extends Post {
-            id: "id",
-            title: "title",
-            thumb: "thumb",
-            updated: "updated",
+    impl PostPreview {
+        /// The columns [`FromQuery::from_row`] reads, for selecting them
+        /// along with others.
+        pub fn columns() -> String {
+            format!(
+                "id,title,thumb,updated,\
+                 (SELECT read_at FROM {VIEWER_SCHEMA}.post_reads \
+                  WHERE post_reads.user = viewer_user() AND post_reads.id = posts.id) AS read_at"
+            )
+        }
+    }
+
+    // Written out instead of `impl_from_query!`, which requires every field
+    // to be a column; the extra fields are filled in after the query.
+    impl FromQuery for PostPreview {
+        type Based = Post;
+
+        fn select_sql() -> String {
+            format!("SELECT {} FROM posts", Self::columns())
+        }
+
+        fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+            Ok(Self {
+                id: row.get("id")?,
+                title: row.get("title")?,
+                thumb: row.get("thumb")?,
+                updated: row.get("updated")?,
+                read_at: row.get("read_at")?,
+                snippet: None,
+            })
         }
     }
 
@@ -53,6 +243,41 @@ pub mod post_preview {
     }
 }
 
+pub mod with_cursor {
+    use post_archiver::*;
+    use serde::Serialize;
+
+    use crate::api::relation::RequireRelations;
+
+    /// A page of items along with the cursor of the page that follows it.
+    #[derive(Debug, Serialize)]
+    pub struct WithCursor<T> {
+        #[serde(flatten)]
+        pub inner: T,
+        /// Absent on the last page, and for orders without cursor support
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub next_cursor: Option<String>,
+    }
+
+    impl<T: RequireRelations> RequireRelations for WithCursor<T> {
+        fn authors(&self) -> Vec<AuthorId> {
+            self.inner.authors()
+        }
+        fn collections(&self) -> Vec<CollectionId> {
+            self.inner.collections()
+        }
+        fn platforms(&self) -> Vec<PlatformId> {
+            self.inner.platforms()
+        }
+        fn tags(&self) -> Vec<TagId> {
+            self.inner.tags()
+        }
+        fn file_metas(&self) -> Vec<FileMetaId> {
+            self.inner.file_metas()
+        }
+    }
+}
+
 mod totalled {
     use post_archiver::{*, query::Totalled};
 
@@ -76,3 +301,270 @@ mod totalled {
         }
     }
 }
+
+pub mod filtered {
+    use post_archiver::{
+        manager::PostArchiverConnection,
+        query::{BaseFilter, FromQuery, Param, Query, Queryer, RawSql, Sortable},
+    };
+
+    /// Raw `WHERE`/`ORDER BY` clauses layered over a post_archiver query
+    /// builder, for filters it does not offer itself.
+    ///
+    /// Extra `WHERE` clauses are also applied to the `.with_total()` count.
+    pub struct Filtered<Q> {
+        inner: Q,
+        wheres: Vec<String>,
+        params: Vec<Param>,
+        per_viewer: bool,
+        seeks: Vec<String>,
+        seek_params: Vec<Param>,
+        orders: Vec<String>,
+    }
+
+    impl<Q> Filtered<Q> {
+        pub fn new(inner: Q) -> Self {
+            Self {
+                inner,
+                wheres: Vec::new(),
+                params: Vec::new(),
+                per_viewer: false,
+                seeks: Vec::new(),
+                seek_params: Vec::new(),
+                orders: Vec::new(),
+            }
+        }
+
+        /// Add a `WHERE` clause, with `params` bound to its placeholders in order.
+        pub fn filter(
+            &mut self,
+            clause: impl Into<String>,
+            params: impl IntoIterator<Item = Param>,
+        ) -> &mut Self {
+            self.wheres.push(clause.into());
+            self.params.extend(params);
+            self
+        }
+
+        /// Add a `WHERE` clause on the viewer's favorites or history. The
+        /// count cache does not notice those change, so the total is then
+        /// counted afresh every time.
+        pub fn filter_per_viewer(
+            &mut self,
+            clause: impl Into<String>,
+            params: impl IntoIterator<Item = Param>,
+        ) -> &mut Self {
+            self.per_viewer = true;
+            self.filter(clause, params)
+        }
+
+        /// Add a `WHERE` clause that positions the page, like a cursor, so it
+        /// is left out of the `.with_total()` count.
+        pub fn seek(
+            &mut self,
+            clause: impl Into<String>,
+            params: impl IntoIterator<Item = Param>,
+        ) -> &mut Self {
+            self.seeks.push(clause.into());
+            self.seek_params.extend(params);
+            self
+        }
+
+        /// Add an `ORDER BY` term, after any `.sort()` chained on top of this query.
+        ///
+        /// Terms cannot bind params of their own, but may reuse a named param
+        /// (e.g. `:search`) already bound by a `WHERE` clause.
+        pub fn order(&mut self, clause: impl Into<String>) -> &mut Self {
+            self.orders.push(clause.into());
+            self
+        }
+
+        fn apply_where<T>(&self, mut sql: RawSql<T>) -> RawSql<T> {
+            let (wheres, params) = &mut sql.where_clause;
+            wheres.extend(self.wheres.iter().cloned());
+            params.extend(self.params.iter().cloned());
+            sql
+        }
+
+        /// Every clause added here: filters, seeks and order.
+        fn apply_all<T>(&self, sql: RawSql<T>) -> RawSql<T> {
+            let mut sql = self.apply_where(sql);
+            let (wheres, params) = &mut sql.where_clause;
+            wheres.extend(self.seeks.iter().cloned());
+            params.extend(self.seek_params.iter().cloned());
+            sql.order_clause.extend(self.orders.iter().cloned());
+            sql
+        }
+
+        /// The clauses of the whole query, for running it with a `SELECT` of
+        /// one's own, e.g. to read more columns than a [`FromQuery`] has.
+        pub fn raw_sql<T: FromQuery<Based = Q::Based>>(&self) -> RawSql<T>
+        where
+            Q: BaseFilter,
+        {
+            self.inner.update_sql(self.apply_all(RawSql::new()))
+        }
+    }
+
+    impl<Q: BaseFilter> BaseFilter for Filtered<Q> {
+        type Based = Q::Based;
+
+        fn update_sql<T: FromQuery<Based = Self::Based>>(&self, sql: RawSql<T>) -> RawSql<T> {
+            self.apply_where(self.inner.update_sql(sql))
+        }
+
+        fn queryer(&self) -> &Queryer<'_, impl PostArchiverConnection> {
+            self.inner.queryer()
+        }
+
+        fn count(&self) -> post_archiver::error::Result<u64> {
+            if !self.per_viewer {
+                return CachedCount(self).count();
+            }
+            let (sql, params) = self
+                .update_sql(RawSql::<Self::Based>::new())
+                .build_count_sql();
+            self.queryer().count(&sql, params)
+        }
+    }
+
+    /// Counts a [`Filtered`] through post_archiver's count cache.
+    struct CachedCount<'a, Q>(&'a Filtered<Q>);
+
+    impl<Q: BaseFilter> BaseFilter for CachedCount<'_, Q> {
+        type Based = Q::Based;
+
+        fn update_sql<T: FromQuery<Based = Self::Based>>(&self, sql: RawSql<T>) -> RawSql<T> {
+            self.0.update_sql(sql)
+        }
+
+        fn queryer(&self) -> &Queryer<'_, impl PostArchiverConnection> {
+            self.0.queryer()
+        }
+    }
+
+    impl<Q: Query> Query for Filtered<Q> {
+        type Wrapper<T> = Q::Wrapper<T>;
+        type Based = Q::Based;
+
+        fn query_with_context<T: FromQuery<Based = Self::Based>>(
+            self,
+            sql: RawSql<T>,
+        ) -> post_archiver::error::Result<Self::Wrapper<T>> {
+            let sql = self.apply_all(sql);
+            self.inner.query_with_context(sql)
+        }
+    }
+
+    impl<Q: Sortable> Sortable for Filtered<Q> {
+        type SortField = Q::SortField;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use cursor::CursorValue;
+
+    use super::*;
+
+    fn parse(value: &str) -> Result<TimeBound, String> {
+        TimeBound::try_from(value.to_string())
+    }
+
+    #[test]
+    fn time_bound_day_covers_whole_day() {
+        let day = parse("2024-03-05").unwrap();
+        assert_eq!(
+            day.start(),
+            Utc.with_ymd_and_hms(2024, 3, 5, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            day.end(),
+            Utc.with_ymd_and_hms(2024, 3, 5, 23, 59, 59).unwrap()
+                + chrono::Duration::nanoseconds(999_999_999)
+        );
+    }
+
+    #[test]
+    fn time_bound_rfc3339_is_converted_to_utc() {
+        let at = parse("2024-03-05T12:00:00+02:00").unwrap();
+        let expected = Utc.with_ymd_and_hms(2024, 3, 5, 10, 0, 0).unwrap();
+        assert_eq!(at, TimeBound::At(expected));
+        assert_eq!(at.start(), at.end());
+    }
+
+    #[test]
+    fn time_bound_rejects_other_formats() {
+        for value in ["", "2024-13-01", "05/03/2024", "2024-03-05 12:00"] {
+            assert!(parse(value).is_err(), "{value} should not parse");
+        }
+    }
+
+    #[test]
+    fn time_bound_round_trips_through_string() {
+        for value in ["2024-03-05", "2024-03-05T10:00:00+00:00"] {
+            let bound = parse(value).unwrap();
+            assert_eq!(String::from(bound), value);
+        }
+    }
+
+    fn pagination(cursor: Option<String>) -> Pagination {
+        Pagination {
+            limit: None,
+            page: Some(3),
+            cursor,
+        }
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        for value in [
+            CursorValue::Integer(-7),
+            CursorValue::Real(0.25),
+            CursorValue::Text("2024-03-05 10:00:00".to_string()),
+        ] {
+            let cursor = Cursor {
+                order: "updated desc".to_string(),
+                value: value.clone(),
+                id: 42,
+            };
+            let decoded = Cursor::decode(&cursor.encode()).unwrap();
+            assert_eq!(decoded.order, "updated desc");
+            assert_eq!(decoded.value, value);
+            assert_eq!(decoded.id, 42);
+        }
+    }
+
+    #[test]
+    fn cursor_from_another_order_is_rejected() {
+        let cursor = Cursor {
+            order: "updated desc".to_string(),
+            value: CursorValue::Integer(1),
+            id: 1,
+        }
+        .encode();
+        let pagination = pagination(Some(cursor));
+
+        assert!(pagination.cursor(Some("updated desc")).unwrap().is_some());
+        assert!(pagination.cursor(Some("title asc")).is_err());
+        // Unseeded random cannot be paged by cursor at all
+        assert!(pagination.cursor(None).is_err());
+    }
+
+    #[test]
+    fn cursor_garbage_is_rejected() {
+        assert!(Cursor::decode("not a cursor").is_none());
+        assert!(
+            pagination(Some("bm90IGpzb24".to_string()))
+                .cursor(Some("id desc"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn cursor_takes_precedence_over_page() {
+        assert_eq!(pagination(None).page(), 3);
+        assert_eq!(pagination(Some(String::new())).page(), 0);
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    cell::Cell,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use rusqlite::{Connection, functions::FunctionFlags};
+
+/// Database owned by the viewer, for state the `query_only` archive has no
+/// room for: the search index, accounts, favorites, reading history and such.
+pub const VIEWER_DATABASE_NAME: &str = "post-archiver-viewer.db";
+
+/// Schema name the viewer database is attached under on archive connections.
+pub const VIEWER_SCHEMA: &str = "viewer";
+
+/// Schema changes, applied in order and tracked by `user_version`.
+const MIGRATIONS: &[&str] = &[
+    // Databases from before versioning already have these, hence IF NOT EXISTS
+    "
+    CREATE TABLE IF NOT EXISTS favorite_posts (
+        id INTEGER NOT NULL PRIMARY KEY,
+        starred DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS favorite_authors (
+        id INTEGER NOT NULL PRIMARY KEY,
+        starred DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS favorite_collections (
+        id INTEGER NOT NULL PRIMARY KEY,
+        starred DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE IF NOT EXISTS post_reads (
+        id INTEGER NOT NULL PRIMARY KEY,
+        read_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        page INTEGER,
+        scroll REAL
+    );
+    ",
+    // Accounts, with existing favorites and history kept for the shared user
+    "
+    CREATE TABLE users (
+        id INTEGER NOT NULL PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+        password TEXT NOT NULL,
+        created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE TABLE sessions (
+        token TEXT NOT NULL PRIMARY KEY,
+        user INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );
+
+    ALTER TABLE favorite_posts RENAME TO old_favorite_posts;
+    CREATE TABLE favorite_posts (
+        user INTEGER NOT NULL,
+        id INTEGER NOT NULL,
+        starred DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (user, id)
+    );
+    INSERT INTO favorite_posts SELECT 0, id, starred FROM old_favorite_posts;
+    DROP TABLE old_favorite_posts;
+
+    ALTER TABLE favorite_authors RENAME TO old_favorite_authors;
+    CREATE TABLE favorite_authors (
+        user INTEGER NOT NULL,
+        id INTEGER NOT NULL,
+        starred DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (user, id)
+    );
+    INSERT INTO favorite_authors SELECT 0, id, starred FROM old_favorite_authors;
+    DROP TABLE old_favorite_authors;
+
+    ALTER TABLE favorite_collections RENAME TO old_favorite_collections;
+    CREATE TABLE favorite_collections (
+        user INTEGER NOT NULL,
+        id INTEGER NOT NULL,
+        starred DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (user, id)
+    );
+    INSERT INTO favorite_collections SELECT 0, id, starred FROM old_favorite_collections;
+    DROP TABLE old_favorite_collections;
+
+    ALTER TABLE post_reads RENAME TO old_post_reads;
+    CREATE TABLE post_reads (
+        user INTEGER NOT NULL,
+        id INTEGER NOT NULL,
+        read_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        page INTEGER,
+        scroll REAL,
+        PRIMARY KEY (user, id)
+    );
+    INSERT INTO post_reads SELECT 0, id, read_at, page, scroll FROM old_post_reads;
+    DROP TABLE old_post_reads;
+    ",
+    // The search index moved to a database of its own
+    "
+    DROP TABLE IF EXISTS posts_fts;
+    DROP TABLE IF EXISTS search_meta;
+    ",
+    // Searches users keep under a name
+    "
+    CREATE TABLE saved_searches (
+        id INTEGER NOT NULL PRIMARY KEY,
+        user INTEGER NOT NULL,
+        name TEXT NOT NULL COLLATE NOCASE,
+        query TEXT NOT NULL,
+        saved DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        UNIQUE (user, name)
+    );
+    ",
+];
+
+/// Owner of favorites and history when accounts are off.
+pub const SHARED_USER: u32 = 0;
+
+thread_local! {
+    static CURRENT_USER: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// Run `f` with `viewer_user()` returning `user` in queries on this thread.
+pub fn as_user<T>(user: Option<u32>, f: impl FnOnce() -> T) -> T {
+    let previous = CURRENT_USER.replace(user);
+    let result = f();
+    CURRENT_USER.set(previous);
+    result
+}
+
+/// The user `viewer_user()` returns on this thread, for binding as a
+/// parameter where a query is cached by its SQL and parameters.
+pub fn query_user() -> Option<u32> {
+    CURRENT_USER.get()
+}
+
+#[derive(Debug)]
+pub struct ViewerDatabase {
+    conn: Mutex<Connection>,
+    path: PathBuf,
+}
+
+impl ViewerDatabase {
+    /// Open or create the viewer database in `dir`.
+    pub fn open(dir: &Path) -> Result<Self, rusqlite::Error> {
+        let path = dir.join(VIEWER_DATABASE_NAME);
+        let mut conn = Connection::open(&path)?;
+
+        conn.execute_batch(
+            "
+            PRAGMA journal_mode = WAL;   -- let archive connections read while we write
+            PRAGMA busy_timeout = 5000;
+            PRAGMA foreign_keys = ON;
+            ",
+        )?;
+
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            path,
+        })
+    }
+
+    /// The writable connection. Archive connections only read the viewer
+    /// database, through [`ViewerDatabase::attach`].
+    pub fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    /// Attach the viewer database to an archive connection as
+    /// [`VIEWER_SCHEMA`], so queries can join against it, along with the
+    /// `viewer_user()` function naming whose favorites and history to use.
+    pub fn attach(&self, conn: &Connection) -> Result<(), rusqlite::Error> {
+        conn.execute(
+            &format!("ATTACH DATABASE ? AS {VIEWER_SCHEMA}"),
+            [self.path.to_string_lossy()],
+        )?;
+        conn.create_scalar_function("viewer_user", 0, FunctionFlags::SQLITE_UTF8, |_| {
+            Ok(CURRENT_USER.get())
+        })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+use std::{convert::Infallible, error::Error, path::Path, sync::mpsc, thread, time::Duration};
+
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use cached::Cached;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use post_archiver::{PostId, manager::ManagerCaches, utils::DATABASE_NAME};
+use rusqlite::Connection;
+use serde::Serialize;
+use tokio_stream::{
+    Stream, StreamExt,
+    wrappers::{BroadcastStream, WatchStream},
+};
+use tracing::{error, info};
+use ts_rs::TS;
+
+use super::AppState;
+
+/// How long to wait for the archiver to finish a burst of writes before
+/// looking at what changed.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Changes queued for a subscriber that is not keeping up.
+pub const EVENTS_CAPACITY: usize = 16;
+
+/// Sent on `/api/events` when the archiver added or updated posts.
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct PostsChanged {
+    pub added: Vec<PostId>,
+    pub updated: Vec<PostId>,
+}
+
+/// The newest post the viewer has seen, to tell what changed since.
+#[derive(Debug, Default)]
+struct Seen {
+    updated: Option<String>,
+    id: Option<u32>,
+}
+
+/// Watch the archive database and its WAL, dropping cached totals and
+/// announcing changed posts whenever the archiver writes to it.
+pub fn watch_archive(path: &Path, state: AppState) -> notify::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher: RecommendedWatcher = notify::recommended_watcher(move |event| {
+        let Ok(notify::Event { kind, paths, .. }) = event else {
+            return;
+        };
+        let written = matches!(kind, EventKind::Create(_) | EventKind::Modify(_));
+        let archive = paths.iter().any(|path| {
+            path.file_name().is_some_and(|name| {
+                let name = name.to_string_lossy();
+                name == DATABASE_NAME || name == format!("{DATABASE_NAME}-wal")
+            })
+        });
+        if written && archive {
+            let _ = tx.send(());
+        }
+    })?;
+    // The WAL comes and goes, so watch the directory rather than the files
+    watcher.watch(path, RecursiveMode::NonRecursive)?;
+
+    thread::spawn(move || {
+        let _watcher = watcher;
+        let mut seen = match connect(&state, latest) {
+            Ok(seen) => seen,
+            Err(err) => {
+                error!("Failed to read the archive, not watching it for changes: {err}");
+                return;
+            }
+        };
+
+        while rx.recv().is_ok() {
+            thread::sleep(DEBOUNCE);
+            while rx.try_recv().is_ok() {}
+
+            state.caches.tables.lock().unwrap().cache_clear();
+            *state.caches.manager.lock().unwrap() = ManagerCaches::default();
+            state.search.mark_stale();
+
+            match connect(&state, |conn| changes(conn, &mut seen)) {
+                Ok(Some(changed)) => {
+                    info!(
+                        "Archive changed: {} posts added, {} updated",
+                        changed.added.len(),
+                        changed.updated.len()
+                    );
+                    // Nobody listening is fine
+                    let _ = state.events.send(changed);
+                }
+                Ok(None) => {}
+                Err(err) => error!("Failed to check the archive for changes: {err}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Run `f` with a pooled archive connection.
+fn connect<T>(
+    state: &AppState,
+    f: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+) -> Result<T, Box<dyn Error>> {
+    let manager = state.pool.get()?;
+    Ok(f(manager.conn())?)
+}
+
+fn latest(conn: &Connection) -> rusqlite::Result<Seen> {
+    conn.query_row("SELECT MAX(updated), MAX(id) FROM posts", [], |row| {
+        Ok(Seen {
+            updated: row.get(0)?,
+            id: row.get(1)?,
+        })
+    })
+}
+
+/// Posts added or updated since `seen`, which is moved forward past them.
+fn changes(conn: &Connection, seen: &mut Seen) -> rusqlite::Result<Option<PostsChanged>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT id, updated FROM posts WHERE updated > ?1 OR id > ?2 ORDER BY id",
+    )?;
+    let rows = stmt.query_map(
+        (
+            seen.updated.as_deref().unwrap_or_default(),
+            seen.id.unwrap_or(0),
+        ),
+        |row| Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?)),
+    )?;
+
+    let mut changed = PostsChanged {
+        added: vec![],
+        updated: vec![],
+    };
+    let last_id = seen.id;
+    for row in rows {
+        let (id, updated) = row?;
+        if last_id.is_none_or(|last| id > last) {
+            changed.added.push(PostId::from(id));
+        } else {
+            changed.updated.push(PostId::from(id));
+        }
+        seen.id = seen.id.max(Some(id));
+        if seen.updated.as_ref().is_none_or(|seen| updated > *seen) {
+            seen.updated = Some(updated);
+        }
+    }
+
+    if changed.added.is_empty() && changed.updated.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(changed))
+}
+
+/// Server-sent events for changes to the archive, named `posts` and carrying
+/// a [`PostsChanged`].
+pub async fn events_handler(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // End the stream on shutdown rather than holding it up
+    let closing = WatchStream::new(state.shutdown.subscribe())
+        .filter(|&closing| closing)
+        .map(|_| None);
+    let stream = BroadcastStream::new(state.events.subscribe())
+        // A lagging client just misses some ids, the next event still arrives
+        .filter_map(|changed| changed.ok())
+        .map(|changed| {
+            Some(Ok(Event::default()
+                .event("posts")
+                .json_data(changed)
+                .unwrap()))
+        })
+        .merge(closing)
+        .map_while(|event| event);
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(posts: &[PostId]) -> Vec<u32> {
+        posts.iter().map(|id| id.raw()).collect()
+    }
+
+    #[test]
+    fn changes_tell_added_from_updated() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "
+            CREATE TABLE posts (id INTEGER PRIMARY KEY, updated TEXT NOT NULL);
+            INSERT INTO posts VALUES (1, '2024-01-01'), (2, '2024-01-02');
+            ",
+        )
+        .unwrap();
+        let mut seen = latest(&conn).unwrap();
+
+        // Nothing written yet
+        assert!(changes(&conn, &mut seen).unwrap().is_none());
+
+        conn.execute_batch(
+            "
+            UPDATE posts SET updated = '2024-02-01' WHERE id = 1;
+            INSERT INTO posts VALUES (3, '2024-01-03');
+            ",
+        )
+        .unwrap();
+        let changed = changes(&conn, &mut seen).unwrap().unwrap();
+        assert_eq!(ids(&changed.added), [3]);
+        assert_eq!(ids(&changed.updated), [1]);
+
+        // Post 2 is left alone and the others were already reported
+        assert!(changes(&conn, &mut seen).unwrap().is_none());
+
+        // Added with an older timestamp than anything seen
+        conn.execute("INSERT INTO posts VALUES (4, '2023-01-01')", [])
+            .unwrap();
+        let changed = changes(&conn, &mut seen).unwrap().unwrap();
+        assert_eq!(ids(&changed.added), [4]);
+        assert!(changed.updated.is_empty());
+    }
+
+    #[test]
+    fn changes_of_an_empty_archive_are_all_added() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE posts (id INTEGER PRIMARY KEY, updated TEXT NOT NULL);")
+            .unwrap();
+        let mut seen = latest(&conn).unwrap();
+
+        conn.execute_batch("INSERT INTO posts VALUES (1, '2024-01-01'), (2, '2024-01-01');")
+            .unwrap();
+        let changed = changes(&conn, &mut seen).unwrap().unwrap();
+        assert_eq!(ids(&changed.added), [1, 2]);
+        assert!(changed.updated.is_empty());
+    }
+}
@@ -0,0 +1,511 @@
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::{
+    Form, Router,
+    extract::{ConnectInfo, FromRequestParts, Query, Request, State},
+    http::{
+        Extensions, HeaderMap, HeaderValue, Method, StatusCode,
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        request::Parts,
+    },
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::get,
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use base64::{
+    Engine,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
+use cached::{Cached, TimedSizedCache};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use serde::Deserialize;
+use tracing::{info, warn};
+use url::{Position, Url, form_urlencoded};
+
+use crate::{api::error::ApiError, config::AuthConfig};
+
+const AUTH_COOKIE: &str = "viewer_auth";
+const SESSION_DAYS: i64 = 30;
+/// Sessions remembered at once, the least recently used are dropped first.
+const MAX_SESSIONS: usize = 1024;
+const LOGIN_PATH: &str = "/login";
+
+/// Wrong passwords or tokens a client may try within [`FAILED_LOGIN_WINDOW`]
+/// before each further one is held back until the window has room again.
+const MAX_FAILED_LOGINS: usize = 10;
+const FAILED_LOGIN_WINDOW: Duration = Duration::from_secs(60);
+/// How long a wrong password or token takes to be answered, to slow down
+/// guessing.
+const FAILED_LOGIN_DELAY: Duration = Duration::from_secs(1);
+/// Clients whose failures are remembered at once.
+const MAX_FAILING_CLIENTS: usize = 1024;
+
+/// Checks every request against the configured [`AuthConfig`], and
+/// remembers browsers that got in with the token or the login page.
+///
+/// Sessions live in memory, so restarting the server logs everyone out.
+#[derive(Debug)]
+struct AuthGate {
+    config: AuthConfig,
+    sessions: Mutex<TimedSizedCache<String, ()>>,
+    /// Session shared by everyone who got in with the token in the query
+    token_session: Mutex<Option<String>>,
+    /// When recent logins failed, oldest first, per client address. Behind a
+    /// unix socket there is none, so all clients count as one.
+    failed_logins: Mutex<TimedSizedCache<Option<IpAddr>, VecDeque<Instant>>>,
+}
+
+/// Put `router` behind the gate, along with the login page when a password
+/// is set. Does nothing if no way to authenticate is configured.
+pub fn protect(router: Router, config: &AuthConfig) -> Router {
+    if !config.enabled() {
+        return router;
+    }
+
+    info!("Access control is enabled");
+    let gate = Arc::new(AuthGate::new(config.clone()));
+
+    let router = if config.auth_password.is_some() {
+        router.merge(
+            Router::new()
+                .route(LOGIN_PATH, get(login_page_handler).post(login_handler))
+                .with_state(gate.clone()),
+        )
+    } else {
+        router
+    };
+    router.layer(middleware::from_fn_with_state(gate, gate_middleware))
+}
+
+impl AuthGate {
+    fn new(config: AuthConfig) -> Self {
+        Self {
+            config,
+            sessions: Mutex::new(TimedSizedCache::with_size_and_lifespan(
+                MAX_SESSIONS,
+                SESSION_DAYS as u64 * 24 * 60 * 60,
+            )),
+            token_session: Mutex::new(None),
+            failed_logins: Mutex::new(TimedSizedCache::with_size_and_lifespan_and_refresh(
+                MAX_FAILING_CLIENTS,
+                FAILED_LOGIN_WINDOW.as_secs(),
+                true,
+            )),
+        }
+    }
+
+    fn has_session(&self, jar: &CookieJar) -> bool {
+        jar.get(AUTH_COOKIE)
+            .is_some_and(|cookie| self.is_session(cookie.value()))
+    }
+
+    fn is_session(&self, session: &str) -> bool {
+        self.sessions.lock().unwrap().cache_get(session).is_some()
+    }
+
+    /// Whether the `Authorization` header holds the right credentials, `None`
+    /// if it holds none of a configured kind.
+    fn check_headers(&self, headers: &HeaderMap) -> Option<bool> {
+        let authorization = headers.get(AUTHORIZATION)?.to_str().ok()?;
+        if let (Some(basic), Some(given)) = (
+            &self.config.auth_basic,
+            authorization.strip_prefix("Basic "),
+        ) {
+            let given = BASE64_STANDARD.decode(given.trim()).unwrap_or_default();
+            return Some(constant_time_eq(&given, basic.as_bytes()));
+        }
+        if let (Some(token), Some(given)) = (
+            &self.config.auth_token,
+            authorization.strip_prefix("Bearer "),
+        ) {
+            return Some(constant_time_eq(given.trim().as_bytes(), token.as_bytes()));
+        }
+        None
+    }
+
+    /// Whether the `token` in the query is the right one, `None` if there is
+    /// no token to check.
+    fn check_query(&self, query: Option<&str>) -> Option<bool> {
+        let token = self.config.auth_token.as_ref()?;
+        let mut given = form_urlencoded::parse(query?.as_bytes())
+            .filter(|(key, _)| key == "token")
+            .peekable();
+        given.peek()?;
+        Some(given.any(|(_, value)| constant_time_eq(value.as_bytes(), token.as_bytes())))
+    }
+
+    fn new_session(&self) -> String {
+        let mut bytes = [0; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let session = BASE64_URL_SAFE_NO_PAD.encode(bytes);
+        self.sessions.lock().unwrap().cache_set(session.clone(), ());
+        session
+    }
+
+    /// The session for a request with the token in the query. Scripts and
+    /// links repeat the token without keeping cookies, so they all share one
+    /// rather than each starting another.
+    fn token_session(&self) -> String {
+        let mut shared = self.token_session.lock().unwrap();
+        match shared.as_deref() {
+            Some(session) if self.is_session(session) => session.to_string(),
+            _ => shared.insert(self.new_session()).clone(),
+        }
+    }
+
+    /// Note a wrong password or token from `client` and hold the answer back:
+    /// a little at first, then until its oldest recent failure leaves
+    /// [`FAILED_LOGIN_WINDOW`] once it reached [`MAX_FAILED_LOGINS`].
+    ///
+    /// Returns whether the client was over the limit.
+    async fn login_failed(&self, client: Option<IpAddr>) -> bool {
+        let (limited, delay) = {
+            let mut clients = self.failed_logins.lock().unwrap();
+            let failed = clients.cache_get_or_set_with(client, VecDeque::new);
+            let now = Instant::now();
+            failed.retain(|at| now.duration_since(*at) < FAILED_LOGIN_WINDOW);
+            failed.push_back(now);
+            if failed.len() > MAX_FAILED_LOGINS {
+                failed.pop_front();
+            }
+
+            match failed.front() {
+                Some(oldest) if failed.len() == MAX_FAILED_LOGINS => (
+                    true,
+                    (FAILED_LOGIN_WINDOW - now.duration_since(*oldest)).max(FAILED_LOGIN_DELAY),
+                ),
+                _ => (false, FAILED_LOGIN_DELAY),
+            }
+        };
+
+        if limited {
+            warn!("Too many failed logins from {}", client_name(client));
+        }
+        tokio::time::sleep(delay).await;
+        limited
+    }
+
+    /// Response for a request that did not get in.
+    fn deny(&self, req: &Request) -> Response {
+        let path = req.uri().path();
+        let is_page = req.method() == Method::GET
+            && !["/api/", "/images/", "/resource/", "/a/"]
+                .iter()
+                .any(|prefix| path.starts_with(prefix));
+        if self.config.auth_password.is_some() && is_page {
+            let next = req.uri().path_and_query().map_or("/", |p| p.as_str());
+            let next = utf8_percent_encode(next, NON_ALPHANUMERIC);
+            return Redirect::to(&format!("{LOGIN_PATH}?next={next}")).into_response();
+        }
+
+        let mut response =
+            ApiError::new(StatusCode::UNAUTHORIZED, "Authentication required").into_response();
+        if self.config.auth_basic.is_some() {
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"Post Archiver\""),
+            );
+        }
+        response
+    }
+}
+
+async fn gate_middleware(
+    State(gate): State<Arc<AuthGate>>,
+    jar: CookieJar,
+    req: Request,
+    next: Next,
+) -> Response {
+    if req.uri().path() == LOGIN_PATH || gate.has_session(&jar) {
+        return next.run(req).await;
+    }
+    let headers = gate.check_headers(req.headers());
+    if headers == Some(true) {
+        return next.run(req).await;
+    }
+
+    // Links like the QR code carry the token, so let the browser keep it
+    let query = gate.check_query(req.uri().query());
+    if query == Some(true) {
+        let jar = jar.add(session_cookie(gate.token_session()));
+        return (jar, next.run(req).await).into_response();
+    }
+
+    if (headers.is_some() || query.is_some())
+        && gate.login_failed(client_ip(req.extensions())).await
+    {
+        return too_many_failures();
+    }
+    gate.deny(&req)
+}
+
+/// Address of the client making the request, if it came in over TCP.
+#[derive(Debug, Clone, Copy)]
+struct ClientIp(Option<IpAddr>);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(client_ip(&parts.extensions)))
+    }
+}
+
+fn client_ip(extensions: &Extensions) -> Option<IpAddr> {
+    extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+}
+
+fn client_name(client: Option<IpAddr>) -> String {
+    client.map_or_else(|| "the socket".to_string(), |ip| ip.to_string())
+}
+
+fn too_many_failures() -> Response {
+    ApiError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        "Too many failed attempts, try again in a minute",
+    )
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+    next: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginForm {
+    password: String,
+    next: Option<String>,
+}
+
+async fn login_page_handler(Query(query): Query<LoginQuery>) -> Html<String> {
+    login_page(query.next.as_deref(), None)
+}
+
+async fn login_handler(
+    State(gate): State<Arc<AuthGate>>,
+    ClientIp(client): ClientIp,
+    jar: CookieJar,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    let password = gate.config.auth_password.as_deref().unwrap_or_default();
+    if !constant_time_eq(form.password.as_bytes(), password.as_bytes()) {
+        warn!("Failed login attempt from {}", client_name(client));
+        if gate.login_failed(client).await {
+            let page = login_page(
+                form.next.as_deref(),
+                Some("Too many failed attempts, try again in a minute"),
+            );
+            return (StatusCode::TOO_MANY_REQUESTS, page).into_response();
+        }
+        let page = login_page(form.next.as_deref(), Some("Wrong password"));
+        return (StatusCode::UNAUTHORIZED, page).into_response();
+    }
+
+    let next = form
+        .next
+        .as_deref()
+        .and_then(local_redirect)
+        .unwrap_or_else(|| "/".to_string());
+    let jar = jar.add(session_cookie(gate.new_session()));
+    (jar, Redirect::to(&next)).into_response()
+}
+
+/// `next` as the path a browser would follow it to, if it stays on this
+/// server. Browsers read `/\host` and the like as `//host`, so `next` is
+/// resolved the way they would rather than checked by its prefix.
+fn local_redirect(next: &str) -> Option<String> {
+    let base = Url::parse("http://viewer.invalid/").unwrap();
+    let url = base.join(next).ok()?;
+    (next.starts_with('/') && url.origin() == base.origin())
+        .then(|| url[Position::BeforePath..].to_string())
+}
+
+fn session_cookie(session: String) -> Cookie<'static> {
+    Cookie::build((AUTH_COOKIE, session))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::days(SESSION_DAYS))
+        .build()
+}
+
+fn login_page(next: Option<&str>, error: Option<&str>) -> Html<String> {
+    let next = html_escape(next.unwrap_or("/"));
+    let error = error
+        .map(|error| format!("<p class=\"error\">{}</p>", html_escape(error)))
+        .unwrap_or_default();
+    Html(format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Post Archiver</title>
+<style>
+body {{ font-family: sans-serif; display: grid; place-items: center; min-height: 100vh; margin: 0; }}
+form {{ display: grid; gap: .75rem; width: 16rem; }}
+.error {{ color: #c00; margin: 0; }}
+</style>
+</head>
+<body>
+<form method="post" action="{LOGIN_PATH}">
+<h1>Post Archiver</h1>
+{error}
+<input type="password" name="password" placeholder="Password" autofocus required>
+<input type="hidden" name="next" value="{next}">
+<button type="submit">Log in</button>
+</form>
+</body>
+</html>
+"#
+    ))
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Compare secrets without leaking how much of them matched through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use super::*;
+
+    fn gate() -> AuthGate {
+        AuthGate::new(AuthConfig {
+            auth_basic: Some("user:pass".to_string()),
+            auth_token: Some("token".to_string()),
+            auth_password: Some("password".to_string()),
+        })
+    }
+
+    fn authorization(value: &str) -> HeaderMap {
+        HeaderMap::from_iter([(AUTHORIZATION, HeaderValue::from_str(value).unwrap())])
+    }
+
+    #[test]
+    fn check_headers_accepts_basic_and_bearer() {
+        let gate = gate();
+        let basic = format!("Basic {}", BASE64_STANDARD.encode("user:pass"));
+        assert_eq!(gate.check_headers(&authorization(&basic)), Some(true));
+        assert_eq!(
+            gate.check_headers(&authorization("Bearer token")),
+            Some(true)
+        );
+
+        let wrong = format!("Basic {}", BASE64_STANDARD.encode("user:nope"));
+        assert_eq!(gate.check_headers(&authorization(&wrong)), Some(false));
+        assert_eq!(gate.check_headers(&authorization("Basic !!!")), Some(false));
+        assert_eq!(
+            gate.check_headers(&authorization("Bearer nope")),
+            Some(false)
+        );
+
+        assert_eq!(gate.check_headers(&HeaderMap::new()), None);
+        assert_eq!(gate.check_headers(&authorization("Digest token")), None);
+    }
+
+    #[test]
+    fn check_headers_ignores_unconfigured_kinds() {
+        let gate = AuthGate::new(AuthConfig {
+            auth_password: Some("password".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(gate.check_headers(&authorization("Bearer token")), None);
+        assert_eq!(gate.check_query(Some("token=token")), None);
+    }
+
+    #[test]
+    fn check_query_finds_the_token() {
+        let gate = gate();
+        assert_eq!(gate.check_query(Some("token=token")), Some(true));
+        assert_eq!(gate.check_query(Some("page=2&token=token")), Some(true));
+        assert_eq!(gate.check_query(Some("token=nope")), Some(false));
+        assert_eq!(gate.check_query(Some("page=2")), None);
+        assert_eq!(gate.check_query(None), None);
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_secrets() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn local_redirect_stays_on_this_server() {
+        assert_eq!(local_redirect("/").as_deref(), Some("/"));
+        assert_eq!(
+            local_redirect("/posts/1?page=2#top").as_deref(),
+            Some("/posts/1?page=2#top")
+        );
+        assert_eq!(local_redirect("/a/../b").as_deref(), Some("/b"));
+
+        for next in [
+            "//evil.example",
+            "/\\evil.example",
+            "/\t/evil.example",
+            "https://evil.example/",
+            "evil.example",
+            "",
+        ] {
+            assert_eq!(local_redirect(next), None, "{next:?}");
+        }
+    }
+
+    fn request(method: Method, uri: &str) -> Request {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn deny_sends_pages_to_the_login() {
+        let response = gate().deny(&request(Method::GET, "/posts/1?page=2"));
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers()[axum::http::header::LOCATION],
+            "/login?next=%2Fposts%2F1%3Fpage%3D2"
+        );
+    }
+
+    #[test]
+    fn deny_answers_the_rest_with_401() {
+        for (method, uri) in [(Method::GET, "/api/summary"), (Method::POST, "/posts")] {
+            let response = gate().deny(&request(method, uri));
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{uri}");
+            assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+        }
+
+        let gate = AuthGate::new(AuthConfig {
+            auth_token: Some("token".to_string()),
+            ..Default::default()
+        });
+        let response = gate.deny(&request(Method::GET, "/posts/1"));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(!response.headers().contains_key(WWW_AUTHENTICATE));
+    }
+}
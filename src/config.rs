@@ -1,22 +1,642 @@
-use std::path::PathBuf;
+use std::{
+    any::TypeId,
+    collections::{HashMap, HashSet},
+    env,
+    ffi::OsString,
+    fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use clap::Parser;
+use clap::{
+    Arg, ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand,
+    error::ErrorKind, parser::ValueSource,
+};
 use image_provider::ResizeConfig;
+use post_archiver::utils::DATABASE_NAME;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 use ts_rs::TS;
+use url::Url;
+
+/// Settings shown masked by `config check`.
+const SECRETS: &[&str] = &["auth_basic", "auth_token", "auth_password"];
 
 #[derive(Debug, Clone, Deserialize, Parser)]
 pub struct Config {
     #[clap(env = "ARCHIVER_PATH", default_value = "archive")]
     pub path: PathBuf,
+    /// Another archive to serve under `/a/{name}/`, given as `name=path`
+    #[clap(long = "archive", env = "VIEWER_ARCHIVES", value_delimiter = ',')]
+    pub archives: Vec<ArchiveSpec>,
+    /// Also serve every archive in this directory, named after its folder
+    #[clap(long, env = "VIEWER_ARCHIVES_DIR")]
+    pub archives_dir: Option<PathBuf>,
+    /// Address to listen on: IPv4, IPv6, or `unix:/path/to/socket`
+    #[clap(
+        long,
+        visible_alias = "host",
+        env = "VIEWER_LISTEN",
+        default_value = "0.0.0.0"
+    )]
+    pub listen: Listen,
     #[clap(long, default_value = "3000")]
     pub port: u16,
+    /// Serve HTTPS with this PEM certificate (chain)
+    #[clap(long, env = "VIEWER_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for `--tls-cert`
+    #[clap(long, env = "VIEWER_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Serve HTTPS with a self-signed certificate, generated on first run and
+    /// kept in the data directory
+    #[clap(long, env = "VIEWER_TLS_SELF_SIGNED", conflicts_with = "tls_cert")]
+    pub tls_self_signed: bool,
+    /// Also listen for plain HTTP on this port, redirecting to HTTPS
+    #[clap(long, env = "VIEWER_HTTP_REDIRECT_PORT")]
+    pub http_redirect_port: Option<u16>,
+    /// Seconds to let running requests finish after Ctrl + C or SIGTERM
+    #[clap(long, env = "VIEWER_SHUTDOWN_TIMEOUT", default_value = "30")]
+    pub shutdown_timeout: u64,
+    /// Where the viewer keeps its own database, defaults to the archive
+    #[clap(long, env = "VIEWER_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Origins allowed to call the server from other sites, e.g.
+    /// `https://example.com`. Any origin, without cookies, when empty
+    #[clap(
+        long = "cors-origin",
+        env = "VIEWER_CORS_ORIGINS",
+        value_delimiter = ','
+    )]
+    pub cors_origins: Vec<String>,
+    /// Sites allowed to show the viewer in a frame, none when empty
+    #[clap(
+        long = "frame-ancestor",
+        env = "VIEWER_FRAME_ANCESTORS",
+        value_delimiter = ','
+    )]
+    pub frame_ancestors: Vec<String>,
 
     #[clap(flatten)]
     pub public: PublicConfig,
 
     #[clap(flatten)]
     pub resize: ResizeConfig,
+
+    #[clap(flatten)]
+    pub auth: AuthConfig,
+
+    /// Read settings from this TOML file, keyed like the fields here or the
+    /// flags. Flags and env take precedence
+    #[clap(long = "config", env = "VIEWER_CONFIG")]
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+
+    /// Every setting and where it came from, see [`Config::load`]
+    #[clap(skip)]
+    #[serde(skip)]
+    pub settings: Vec<Setting>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Inspect the configuration
+    Config {
+        #[clap(subcommand)]
+        action: ConfigCommand,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective configuration as TOML and check it
+    Check,
+}
+
+/// Where a setting got its value, in order of precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Cli,
+    Env,
+    File,
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cli => "command line",
+            Self::Env => "environment",
+            Self::File => "config file",
+            Self::Default => "default",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Setting {
+    pub key: String,
+    /// Rendered as a TOML value
+    pub value: String,
+    pub source: Source,
+}
+
+impl Config {
+    /// Parse flags and env, then take whatever neither of them set from the
+    /// `--config` file, falling back to the defaults. Exits on invalid flags
+    /// like [`Parser::parse`].
+    pub fn load() -> Result<Self, String> {
+        Self::load_from(env::args_os().collect(), &env::vars_os().collect())
+    }
+
+    /// [`Config::load`] from the given command line and environment instead
+    /// of this process's.
+    fn load_from(
+        mut args: Vec<OsString>,
+        vars: &HashMap<OsString, OsString>,
+    ) -> Result<Self, String> {
+        let mut matches = parse(&args).unwrap_or_else(|err| match err.kind() {
+            // Again with the env vars, so the help lists them
+            ErrorKind::DisplayHelp => Self::command().get_matches_from(&args),
+            _ => err.exit(),
+        });
+
+        // Passed as flags like the file's below, unless given on the
+        // command line
+        let mut env_args = vec![];
+        let mut from_env = HashSet::new();
+        for arg in Self::command().get_arguments() {
+            let Some(value) = arg.get_env().and_then(|name| vars.get(name)) else {
+                continue;
+            };
+            let id = arg.get_id().to_string();
+            if matches.value_source(&id) == Some(ValueSource::CommandLine) {
+                continue;
+            }
+            let name = arg.get_env().unwrap_or_default().to_string_lossy();
+            let value = value
+                .to_str()
+                .ok_or_else(|| format!("{name} is not valid UTF-8"))?;
+            push_setting(arg, &name, value.to_string(), &mut env_args)?;
+            from_env.insert(id);
+        }
+        if !env_args.is_empty() {
+            args.splice(1..1, env_args);
+            matches = parse(&args).unwrap_or_else(|err| err.exit());
+        }
+
+        let mut from_file = HashSet::new();
+        if let Some(file) = matches.get_one::<PathBuf>("config_file") {
+            let text =
+                fs::read_to_string(file).map_err(|err| format!("{}: {err}", file.display()))?;
+            let table: toml::Table = text
+                .parse()
+                .map_err(|err| format!("{}: {err}", file.display()))?;
+
+            // Passed as flags before any given ones, so clap parses and
+            // checks them like the rest
+            let mut file_args = vec![];
+            for (key, value) in table {
+                let id = file_setting(&matches, &key, &value, &mut file_args)
+                    .map_err(|err| format!("{}: {err}", file.display()))?;
+                if let Some(id) = id {
+                    from_file.insert(id);
+                }
+            }
+            args.splice(1..1, file_args);
+            matches = parse(&args).map_err(|err| {
+                // Only the first line, the rest is about flags
+                let err = err.render().to_string();
+                let err = err.lines().next().unwrap_or_default();
+                format!("{}: {}", file.display(), err.trim_start_matches("error: "))
+            })?;
+        }
+
+        let mut config = Self::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        config.settings = settings(&matches, &from_env, &from_file);
+        Ok(config)
+    }
+
+    /// Problems that would keep the server from working as configured.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if !self.path.join(DATABASE_NAME).exists() {
+            problems.push(format!("no archive found at {}", self.path.display()));
+        }
+        for ArchiveSpec { name, path } in &self.archives {
+            if !path.join(DATABASE_NAME).exists() {
+                problems.push(format!("no archive found for {name} at {}", path.display()));
+            }
+        }
+        if let Some(dir) = &self.archives_dir
+            && !dir.is_dir()
+        {
+            problems.push(format!("archives_dir {} is not a directory", dir.display()));
+        }
+
+        let urls = [
+            ("resource_url", self.public.resource_url.as_slice()),
+            ("images_url", self.public.images_url.as_slice()),
+        ];
+        for (key, values) in urls {
+            for value in values {
+                let scheme = Url::parse(value).map(|url| url.scheme().to_string());
+                if !matches!(scheme.as_deref(), Ok("http") | Ok("https")) {
+                    problems.push(format!("{key} is not an http(s) URL: {value}"));
+                }
+            }
+        }
+        // Sent back as is in headers, so nothing the URL parser would tidy up
+        for origin in &self.cors_origins {
+            let parsed = Url::parse(origin).ok().filter(|url| {
+                matches!(url.scheme(), "http" | "https")
+                    && url.origin().ascii_serialization() == *origin
+            });
+            if parsed.is_none() {
+                problems.push(format!(
+                    "cors_origins is not an origin like https://example.com: {origin}"
+                ));
+            }
+        }
+        for source in &self.frame_ancestors {
+            let valid = !source.is_empty()
+                && source
+                    .bytes()
+                    .all(|b| b.is_ascii_graphic() && b != b';' && b != b',');
+            if !valid {
+                problems.push(format!(
+                    "frame_ancestors is not a source like https://example.com or 'self': {source}"
+                ));
+            }
+        }
+
+        for (key, file) in [("tls_cert", &self.tls_cert), ("tls_key", &self.tls_key)] {
+            if let Some(file) = file
+                && !file.is_file()
+            {
+                problems.push(format!("{key} {} does not exist", file.display()));
+            }
+        }
+        problems
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        self.data_dir.as_deref().unwrap_or(&self.path)
+    }
+
+    pub fn tls(&self) -> bool {
+        self.tls_cert.is_some() || self.tls_self_signed
+    }
+
+    /// Every archive to serve: the one at `path` first, then those from
+    /// `--archive` and `--archives-dir`.
+    pub fn all_archives(&self) -> Result<Vec<Archive>, String> {
+        let name = fs::canonicalize(&self.path)
+            .ok()
+            .and_then(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "archive".to_string());
+        let mut archives = vec![Archive {
+            name,
+            base: String::new(),
+            path: self.path.clone(),
+            data_dir: self.data_dir().to_path_buf(),
+            public: self.public.clone(),
+        }];
+
+        let mut specs = self.archives.clone();
+        if let Some(dir) = &self.archives_dir {
+            let root = fs::canonicalize(&self.path).ok();
+            let mut found = vec![];
+            for entry in fs::read_dir(dir).map_err(|err| format!("{}: {err}", dir.display()))? {
+                let path = entry.map_err(|err| err.to_string())?.path();
+                if !path.join(DATABASE_NAME).exists() || fs::canonicalize(&path).ok() == root {
+                    continue;
+                }
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                match ArchiveSpec::from_str(&format!("{name}={}", path.display())) {
+                    Ok(spec) => found.push(spec),
+                    Err(err) => warn!("Skipping {}: {err}", path.display()),
+                }
+            }
+            found.sort_by(|a, b| a.name.cmp(&b.name));
+            specs.extend(found);
+        }
+
+        let mut names: HashSet<String> = HashSet::from([archives[0].name.clone()]);
+        for ArchiveSpec { name, path } in specs {
+            if !names.insert(name.clone()) {
+                return Err(format!("two archives are named {name}"));
+            }
+            if !path.join(DATABASE_NAME).exists() {
+                return Err(format!("no archive found at {}", path.display()));
+            }
+
+            // Viewer databases of one data directory would collide
+            let data_dir = match &self.data_dir {
+                Some(dir) => dir.join(&name),
+                None => path.clone(),
+            };
+            fs::create_dir_all(&data_dir)
+                .map_err(|err| format!("{}: {err}", data_dir.display()))?;
+
+            archives.push(Archive {
+                base: format!("/a/{name}"),
+                name,
+                path,
+                data_dir,
+                // Served by this server, whatever the main archive uses
+                public: PublicConfig {
+                    resource_url: None,
+                    images_url: None,
+                    ..self.public.clone()
+                },
+            });
+        }
+
+        Ok(archives)
+    }
+}
+
+/// [`Config::command`] parsing `args` without reading the environment, which
+/// [`Config::load_from`] takes from the vars it is given instead.
+fn parse(args: &[OsString]) -> Result<ArgMatches, clap::Error> {
+    Config::command()
+        .mut_args(|arg| arg.env(None::<&str>))
+        .try_get_matches_from(args)
+}
+
+/// Turn `key = value` from the config file into flags on `args`, unless the
+/// command line or env already set it. Returns the id of the setting taken.
+fn file_setting(
+    matches: &ArgMatches,
+    key: &str,
+    value: &toml::Value,
+    args: &mut Vec<OsString>,
+) -> Result<Option<String>, String> {
+    let normalize = |name: &str| name.replace('-', "_");
+    let command = Config::command();
+    let arg = command
+        .get_arguments()
+        .filter(|arg| arg.get_id() != "config_file")
+        .find(|arg| {
+            normalize(arg.get_id().as_str()) == normalize(key)
+                || arg
+                    .get_long()
+                    .is_some_and(|long| normalize(long) == normalize(key))
+        })
+        .ok_or_else(|| format!("unknown setting {key}"))?;
+    let id = arg.get_id().to_string();
+
+    // Env values are passed as flags too
+    if matches.value_source(&id) == Some(ValueSource::CommandLine) {
+        return Ok(None);
+    }
+
+    let values = match value {
+        toml::Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+    };
+    for value in values {
+        let value = match value {
+            toml::Value::String(value) => value.clone(),
+            toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+                value.to_string()
+            }
+            _ => return Err(format!("{key} must be a string, number or boolean")),
+        };
+
+        push_setting(arg, key, value, args)?;
+    }
+    Ok(Some(id))
+}
+
+/// Pass `value` of `arg` as a flag on `args`, or as itself if positional.
+fn push_setting(
+    arg: &Arg,
+    key: &str,
+    value: String,
+    args: &mut Vec<OsString>,
+) -> Result<(), String> {
+    match (arg.get_action(), arg.get_long()) {
+        (ArgAction::SetTrue, Some(long)) => match value.as_str() {
+            "true" => args.push(format!("--{long}").into()),
+            "false" => {}
+            _ => return Err(format!("{key} must be true or false")),
+        },
+        (_, Some(long)) => args.push(format!("--{long}={value}").into()),
+        (_, None) => args.push(value.into()),
+    }
+    Ok(())
+}
+
+/// The value and source of every setting that has one.
+fn settings(
+    matches: &ArgMatches,
+    from_env: &HashSet<String>,
+    from_file: &HashSet<String>,
+) -> Vec<Setting> {
+    let command = Config::command();
+    let mut settings = vec![];
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if id == "config_file" {
+            continue;
+        }
+        let Some(source) = matches.value_source(id) else {
+            continue;
+        };
+        let source = match source {
+            _ if from_env.contains(id) => Source::Env,
+            _ if from_file.contains(id) => Source::File,
+            ValueSource::DefaultValue => Source::Default,
+            _ => Source::Cli,
+        };
+
+        let key = id.replace('-', "_");
+        let raw: Vec<String> = matches
+            .get_raw(id)
+            .into_iter()
+            .flatten()
+            .map(|value| value.to_string_lossy().into_owned())
+            .collect();
+        let value = if SECRETS.contains(&key.as_str()) {
+            toml::Value::from("********")
+        } else if matches!(arg.get_action(), ArgAction::SetTrue) {
+            toml::Value::from(matches.get_flag(id))
+        } else if matches!(arg.get_action(), ArgAction::Append) {
+            toml::Value::Array(raw.into_iter().map(|raw| toml_value(arg, raw)).collect())
+        } else {
+            toml_value(arg, raw.concat())
+        };
+
+        settings.push(Setting {
+            key,
+            value: value.to_string(),
+            source,
+        });
+    }
+    settings
+}
+
+/// `raw` as a TOML value of the type `arg` is parsed to, so numbers are not
+/// written as strings.
+fn toml_value(arg: &Arg, raw: String) -> toml::Value {
+    let parsed = arg.get_value_parser().type_id();
+    let is_any = |types: &[TypeId]| types.iter().any(|ty| parsed == *ty);
+
+    let integers = [
+        TypeId::of::<u8>(),
+        TypeId::of::<u16>(),
+        TypeId::of::<u32>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<usize>(),
+        TypeId::of::<i8>(),
+        TypeId::of::<i16>(),
+        TypeId::of::<i32>(),
+        TypeId::of::<i64>(),
+        TypeId::of::<isize>(),
+    ];
+    if is_any(&integers)
+        && let Ok(value) = raw.parse::<i64>()
+    {
+        return toml::Value::Integer(value);
+    }
+    if is_any(&[TypeId::of::<f32>(), TypeId::of::<f64>()])
+        && let Ok(value) = raw.parse::<f64>()
+    {
+        return toml::Value::Float(value);
+    }
+    toml::Value::String(raw)
+}
+
+/// An archive to serve and the settings it is served with.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub name: String,
+    /// Prefix of its `api`, `resource` and `images` routes, empty for the
+    /// main archive
+    pub base: String,
+    pub path: PathBuf,
+    pub data_dir: PathBuf,
+    pub public: PublicConfig,
+}
+
+/// `--archive name=path`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ArchiveSpec {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl FromStr for ArchiveSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, path)) = s.split_once('=') else {
+            return Err(format!("expected name=path, got {s}"));
+        };
+        // The name goes into URLs
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(format!(
+                "archive names may only use letters, digits, - and _, got {name}"
+            ));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            path: path.into(),
+        })
+    }
+}
+
+impl TryFrom<String> for ArchiveSpec {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Where the server accepts connections.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Listen {
+    /// TCP on `--port`
+    Ip(IpAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return match path.is_empty() {
+                true => Err("missing path after unix:".to_string()),
+                false => Ok(Self::Unix(path.into())),
+            };
+            #[cfg(not(unix))]
+            return Err(format!("unix sockets are not supported here: {path}"));
+        }
+
+        // Allow the bracketed form from URLs, e.g. [::1]
+        let ip = s
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .unwrap_or(s);
+        ip.parse()
+            .map(Self::Ip)
+            .map_err(|_| format!("expected an IPv4 or IPv6 address, or unix:/path, got {s}"))
+    }
+}
+
+impl TryFrom<String> for Listen {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "{ip}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Ways to get past the gate in front of the whole server. It is only up when
+/// at least one is set, and any of them lets a request through.
+#[derive(Debug, Clone, Default, Deserialize, Parser)]
+pub struct AuthConfig {
+    /// Accept HTTP Basic auth, given as `user:password`
+    #[clap(long, env = "VIEWER_AUTH_BASIC")]
+    pub auth_basic: Option<String>,
+
+    /// Accept this token as `Authorization: Bearer <token>` or `?token=`
+    #[clap(long, env = "VIEWER_AUTH_TOKEN")]
+    pub auth_token: Option<String>,
+
+    /// Ask for this password on a login page
+    #[clap(long, env = "VIEWER_AUTH_PASSWORD")]
+    pub auth_password: Option<String>,
+}
+
+impl AuthConfig {
+    pub fn enabled(&self) -> bool {
+        self.auth_basic.is_some() || self.auth_token.is_some() || self.auth_password.is_some()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Parser, TS)]
@@ -31,4 +651,201 @@ pub struct PublicConfig {
     #[clap(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub images_url: Option<String>,
+
+    /// Let people sign up and log in, each with their own favorites and
+    /// reading history
+    #[clap(long, env = "VIEWER_ACCOUNTS")]
+    #[serde(default)]
+    pub accounts: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn load(args: &[&str]) -> Config {
+        load_with_env(args, &[])
+    }
+
+    fn load_with_env(args: &[&str], vars: &[(&str, &str)]) -> Config {
+        let args = ["post-archiver-viewer"]
+            .iter()
+            .chain(args)
+            .map(OsString::from)
+            .collect();
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.into(), value.into()))
+            .collect();
+        Config::load_from(args, &vars).unwrap()
+    }
+
+    /// The rendered value and source of setting `key`.
+    fn setting(config: &Config, key: &str) -> (String, Source) {
+        let setting = config
+            .settings
+            .iter()
+            .find(|setting| setting.key == key)
+            .unwrap();
+        (setting.value.clone(), setting.source)
+    }
+
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("viewer-{name}-{}.toml", std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn settings_prefer_cli_then_env_then_file_then_default() {
+        let env = [("VIEWER_SHUTDOWN_TIMEOUT", "20")];
+        let file = config_file("precedence", "shutdown_timeout = 10\n");
+        let file = file.to_str().unwrap();
+
+        let config = load(&[]);
+        assert_eq!(config.shutdown_timeout, 30);
+        assert_eq!(
+            setting(&config, "shutdown_timeout"),
+            ("30".into(), Source::Default)
+        );
+
+        let config = load(&["--config", file]);
+        assert_eq!(config.shutdown_timeout, 10);
+        assert_eq!(
+            setting(&config, "shutdown_timeout"),
+            ("10".into(), Source::File)
+        );
+
+        let config = load_with_env(&["--config", file], &env);
+        assert_eq!(config.shutdown_timeout, 20);
+        assert_eq!(
+            setting(&config, "shutdown_timeout"),
+            ("20".into(), Source::Env)
+        );
+
+        let config = load_with_env(&["--config", file, "--shutdown-timeout", "5"], &env);
+        assert_eq!(config.shutdown_timeout, 5);
+        assert_eq!(
+            setting(&config, "shutdown_timeout"),
+            ("5".into(), Source::Cli)
+        );
+    }
+
+    #[test]
+    fn check_reports_bad_origins_and_frame_ancestors() {
+        let problems = |args: &[&str]| -> Vec<String> {
+            load(args)
+                .check()
+                .into_iter()
+                .filter(|problem| !problem.starts_with("no archive"))
+                .collect()
+        };
+
+        let good = [
+            "--cors-origin",
+            "https://a.example,http://b.example:8080",
+            "--frame-ancestor",
+            "'self',https://*.example.com",
+        ];
+        assert_eq!(problems(&good), Vec::<String>::new());
+
+        for origin in [
+            "https://a.example/",
+            "https://a.example/path",
+            "ftp://a.example",
+            "a.example",
+            "https://a.example\n",
+        ] {
+            let problems = problems(&["--cors-origin", origin]);
+            assert_eq!(problems.len(), 1, "{origin:?}");
+            assert!(problems[0].starts_with("cors_origins"), "{origin:?}");
+        }
+
+        for source in ["'self'; script-src *", "https://a.example\n", "a b", ""] {
+            let problems = problems(&["--frame-ancestor", source]);
+            assert_eq!(problems.len(), 1, "{source:?}");
+            assert!(problems[0].starts_with("frame_ancestors"), "{source:?}");
+        }
+    }
+
+    #[test]
+    fn settings_render_toml_types() {
+        let file = config_file(
+            "types",
+            "port = 4000\ncors_origins = [\"https://a.example\"]\n",
+        );
+        let config = load(&["--config", file.to_str().unwrap(), "--data-dir", "123"]);
+
+        assert_eq!(setting(&config, "port").0, "4000");
+        assert_eq!(setting(&config, "tls_self_signed").0, "false");
+        assert_eq!(
+            setting(&config, "cors_origins").0,
+            r#"["https://a.example"]"#
+        );
+        // Strings stay strings even when they look like numbers
+        assert_eq!(setting(&config, "data_dir").0, r#""123""#);
+    }
+
+    #[test]
+    fn archive_spec_parses_name_and_path() {
+        assert_eq!(
+            "comics=/srv/comics".parse::<ArchiveSpec>(),
+            Ok(ArchiveSpec {
+                name: "comics".to_string(),
+                path: "/srv/comics".into(),
+            })
+        );
+        // Only the first = separates the name
+        assert_eq!(
+            "a_b-1=dir=with=equals"
+                .parse::<ArchiveSpec>()
+                .map(|spec| spec.path),
+            Ok(PathBuf::from("dir=with=equals"))
+        );
+    }
+
+    #[test]
+    fn archive_spec_rejects_bad_names() {
+        assert!("/srv/comics".parse::<ArchiveSpec>().is_err());
+        assert!("=/srv/comics".parse::<ArchiveSpec>().is_err());
+        assert!("my comics=/srv/comics".parse::<ArchiveSpec>().is_err());
+        assert!("../up=/srv/comics".parse::<ArchiveSpec>().is_err());
+        assert!("bad.name=/srv/comics".parse::<ArchiveSpec>().is_err());
+    }
+
+    #[test]
+    fn listen_parses_ip_addresses() {
+        assert_eq!(
+            "127.0.0.1".parse::<Listen>(),
+            Ok(Listen::Ip(Ipv4Addr::LOCALHOST.into()))
+        );
+        assert_eq!(
+            "::1".parse::<Listen>(),
+            Ok(Listen::Ip(Ipv6Addr::LOCALHOST.into()))
+        );
+        assert_eq!(
+            "[::1]".parse::<Listen>(),
+            Ok(Listen::Ip(Ipv6Addr::LOCALHOST.into()))
+        );
+    }
+
+    #[test]
+    fn listen_rejects_other_addresses() {
+        assert!("localhost".parse::<Listen>().is_err());
+        assert!("127.0.0.1:3000".parse::<Listen>().is_err());
+        assert!("[127.0.0.1".parse::<Listen>().is_err());
+        assert!("".parse::<Listen>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn listen_parses_unix_sockets() {
+        assert_eq!(
+            "unix:/run/viewer.sock".parse::<Listen>(),
+            Ok(Listen::Unix("/run/viewer.sock".into()))
+        );
+        assert!("unix:".parse::<Listen>().is_err());
+    }
 }
@@ -3,40 +3,91 @@ use std::sync::Arc;
 use axum::{
     Router,
     extract::State,
-    http::Uri,
+    http::{
+        HeaderValue, Uri,
+        header::{CONTENT_SECURITY_POLICY, REFERRER_POLICY},
+    },
     response::{Html, IntoResponse},
     routing::get,
 };
+use base64::{Engine, prelude::BASE64_STANDARD};
 use rust_embed::Embed;
+use sha2::{Digest, Sha256};
+use tower_http::set_header::SetResponseHeaderLayer;
 use tracing::info;
+use url::Url;
 
-use crate::config::PublicConfig;
+use crate::config::{Config, PublicConfig};
 
 #[derive(Embed)]
 #[folder = "frontend/dist/"]
 struct Assets;
 
-pub fn frontend(config: &PublicConfig) -> Router<()> {
+pub fn frontend(config: &Config) -> Router<()> {
+    let public = &config.public;
     if cfg!(debug_assertions) {
         use axum_reverse_proxy::ReverseProxy;
         let proxy: ReverseProxy = ReverseProxy::new("/", "http://localhost:5173");
         info!("Running in debug mode");
         info!("Proxying to localhost:5173");
 
-        let config = config.clone();
+        // No security headers here, the vite dev server relies on inline
+        // scripts and eval for HMR
+        let config = public.clone();
         let get_config = async |State(config): State<PublicConfig>| axum::Json(config);
 
         Router::from(proxy)
             .route("/config.json", get(get_config))
             .with_state(config)
     } else {
-        let index_html = load_index_html(config);
+        let (index_html, script) = load_index_html(public);
+        let csp = content_security_policy(config, &script);
         Router::new()
             .fallback(get(static_handler))
             .with_state(index_html)
+            .layer(SetResponseHeaderLayer::if_not_present(
+                CONTENT_SECURITY_POLICY,
+                HeaderValue::try_from(csp).expect("frame_ancestors are checked in Config::check"),
+            ))
+            .layer(SetResponseHeaderLayer::if_not_present(
+                REFERRER_POLICY,
+                HeaderValue::from_static("same-origin"),
+            ))
     }
 }
 
+/// Policy for the frontend: everything from this server, except files and
+/// images when `resource_url` or `images_url` serve them from elsewhere.
+fn content_security_policy(config: &Config, script: &str) -> String {
+    let public = &config.public;
+    let external = [&public.resource_url, &public.images_url]
+        .into_iter()
+        .flatten()
+        .filter_map(|url| Url::parse(url).ok())
+        .map(|url| format!(" {}", url.origin().ascii_serialization()))
+        .collect::<String>();
+
+    let script_hash = BASE64_STANDARD.encode(Sha256::digest(script));
+    let frame_ancestors = match config.frame_ancestors.is_empty() {
+        true => "'none'".to_string(),
+        false => config.frame_ancestors.join(" "),
+    };
+
+    [
+        "default-src 'self'".to_string(),
+        format!("script-src 'self' 'sha256-{script_hash}'"),
+        "style-src 'self' 'unsafe-inline'".to_string(),
+        format!("img-src 'self' data: blob:{external}"),
+        format!("media-src 'self' blob:{external}"),
+        // The ZIP viewer fetches archives from wherever files are served
+        format!("connect-src 'self'{external}"),
+        "object-src 'none'".to_string(),
+        "base-uri 'self'".to_string(),
+        format!("frame-ancestors {frame_ancestors}"),
+    ]
+    .join("; ")
+}
+
 async fn static_handler(State(index_html): State<Arc<String>>, uri: Uri) -> impl IntoResponse {
     let path = uri.path().trim_start_matches('/');
 
@@ -53,12 +104,18 @@ async fn static_handler(State(index_html): State<Arc<String>>, uri: Uri) -> impl
 }
 
 const INDEX_HTML: &str = "index.html";
-fn load_index_html(config: &PublicConfig) -> Arc<String> {
+/// The page with the public config filled in, and the inline script that
+/// carries it, for the CSP hash.
+fn load_index_html(config: &PublicConfig) -> (Arc<String>, String) {
     let file = Assets::get(INDEX_HTML).unwrap();
     let text = String::from_utf8(file.data.to_vec()).unwrap();
 
     // Replace placeholder to real public config
     let config = serde_json::to_string(config).unwrap();
-    let script = format!("<script>window.PUBLIC_CONFIG={config}</script>");
-    Arc::new(text.replace("<!--PUBLIC_CONFIG-->", &script))
+    let script = format!("window.PUBLIC_CONFIG={config}");
+    let html = text.replace(
+        "<!--PUBLIC_CONFIG-->",
+        &format!("<script>{script}</script>"),
+    );
+    (Arc::new(html), script)
 }
@@ -1,24 +1,37 @@
 mod api;
+mod auth;
 pub mod config;
 pub mod frontend;
 pub mod resource;
+mod shutdown;
+mod tls;
 
-use api::get_api_router;
-use axum::http::{HeaderValue, header::X_CONTENT_TYPE_OPTIONS};
-use clap::Parser;
-use config::Config;
+use api::get_api_routers;
+use axum::http::{
+    HeaderValue, Method,
+    header::{AUTHORIZATION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+};
+use axum::middleware;
+use config::{Command, Config, ConfigCommand, Listen};
 use console::style;
 use dotenv::dotenv;
 use frontend::frontend;
 use image_provider::get_images_router;
 use local_ip_address::local_ip;
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
 use qrcode::{QrCode, render::unicode};
 use resource::get_resource_router;
-use std::net::SocketAddr;
+use shutdown::{Shutdown, track_in_flight};
+#[cfg(unix)]
+use std::{fs, os::unix::fs::FileTypeExt};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 use tower::ServiceBuilder;
 use tower_http::{
     compression::CompressionLayer,
-    cors::{Any, CorsLayer},
+    cors::{AllowOrigin, Any, CorsLayer},
     set_header::SetResponseHeaderLayer,
     trace::TraceLayer,
 };
@@ -41,7 +54,21 @@ async fn main() {
     tracing_subscriber::fmt().event_format(format).init();
 
     dotenv().ok();
-    let config = Config::parse();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("Failed to load config: {err}");
+            return;
+        }
+    };
+
+    if let Some(Command::Config {
+        action: ConfigCommand::Check,
+    }) = &config.command
+    {
+        check_config(&config);
+        return;
+    }
 
     info!("# {} #", style("Post Archiver").green().bold());
     info!("==========================");
@@ -53,44 +80,174 @@ async fn main() {
             .bold()
     );
 
-    if !config.path.join("post-archiver.db").exists() {
-        error!("Post Archiver is not found");
+    let problems = config.check();
+    for problem in &problems {
+        error!("Invalid config: {problem}");
+    }
+    if !problems.is_empty() {
         return;
     }
 
-    let images_router = get_images_router(config.path.clone(), config.resize.clone());
-    let resource_router = get_resource_router(&config);
-    let api_router = get_api_router(&config);
-
-    let app = frontend(&config.public)
-        .nest("/api", api_router)
-        .layer(CompressionLayer::new())
-        .nest("/images", images_router)
-        .nest("/resource", resource_router)
-        .layer(
-            ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
-                .layer(CorsLayer::new().allow_origin(Any))
-                .layer(SetResponseHeaderLayer::overriding(
-                    X_CONTENT_TYPE_OPTIONS,
-                    HeaderValue::from_static("nosniff"),
-                )),
-        );
+    let archives = match config.all_archives() {
+        Ok(archives) => archives,
+        Err(err) => {
+            error!("Failed to load archives: {err}");
+            return;
+        }
+    };
+    for archive in &archives[1..] {
+        info!("Serving {} at {}", archive.name, archive.base);
+    }
 
-    let port = config.port;
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    info!("");
+    let shutdown = Shutdown::new(Duration::from_secs(config.shutdown_timeout));
+    let api_routers = get_api_routers(&config, &archives, shutdown.clone());
 
-    info!(
-        " {} http://localhost:{} ",
-        style("Local").green().bold(),
-        port
+    let mut app = frontend(&config).layer(CompressionLayer::new());
+    for (archive, api_router) in archives.iter().zip(api_routers) {
+        let base = &archive.base;
+        app = app
+            .nest(
+                &format!("{base}/api"),
+                api_router.layer(CompressionLayer::new()),
+            )
+            .nest(
+                &format!("{base}/images"),
+                get_images_router(archive.path.clone(), config.resize.clone()),
+            )
+            .nest(&format!("{base}/resource"), get_resource_router(archive));
+    }
+
+    let app = auth::protect(app, &config.auth).layer(
+        ServiceBuilder::new()
+            .layer(middleware::from_fn_with_state(
+                shutdown.clone(),
+                track_in_flight,
+            ))
+            .layer(TraceLayer::new_for_http())
+            .layer(cors_layer(&config.cors_origins))
+            .layer(SetResponseHeaderLayer::overriding(
+                X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            )),
     );
-    if let Ok(addr) = local_ip() {
-        let url = format!("http://{addr}:{port}");
+
+    match &config.listen {
+        Listen::Ip(ip) => {
+            let addr = SocketAddr::new(*ip, config.port);
+            let listener = match tokio::net::TcpListener::bind(addr).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!("Failed to listen on {addr}: {err}");
+                    return;
+                }
+            };
+
+            if !config.tls() {
+                print_banner(&config);
+                let app = app.into_make_service_with_connect_info::<SocketAddr>();
+                let server =
+                    axum::serve(listener, app).with_graceful_shutdown(shutdown.triggered());
+                shutdown.run(server.into_future()).await;
+                return;
+            }
+
+            let tls = match tls::rustls_config(&config).await {
+                Ok(tls) => tls,
+                Err(err) => {
+                    error!("Failed to set up TLS: {err}");
+                    return;
+                }
+            };
+            if let Some(port) = config.http_redirect_port {
+                tokio::spawn(tls::redirect_http(*ip, port, config.port, shutdown.clone()));
+            }
+            print_banner(&config);
+            let handle = axum_server::Handle::new();
+            let triggered = shutdown.triggered();
+            let stopping = handle.clone();
+            tokio::spawn(async move {
+                triggered.await;
+                stopping.graceful_shutdown(None);
+            });
+            let server = axum_server::from_tcp_rustls(listener.into_std().unwrap(), tls)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+            shutdown.run(server).await;
+        }
+        #[cfg(unix)]
+        Listen::Unix(path) => {
+            if config.tls() {
+                error!("TLS is not supported on unix sockets, leave it to the proxy in front");
+                return;
+            }
+            // A socket left behind by an earlier run would make bind fail
+            if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                let _ = fs::remove_file(path);
+            }
+            let listener = match tokio::net::UnixListener::bind(path) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!("Failed to listen on {}: {err}", path.display());
+                    return;
+                }
+            };
+            print_banner(&config);
+            let server = axum::serve(listener, app).with_graceful_shutdown(shutdown.triggered());
+            shutdown.run(server.into_future()).await;
+        }
+    }
+}
+
+/// `config check`: print the effective configuration, which can be used as a
+/// config file, then whatever is wrong with it.
+fn check_config(config: &Config) {
+    for setting in &config.settings {
+        println!("{} = {}  # {}", setting.key, setting.value, setting.source);
+    }
+
+    let problems = config.check();
+    if problems.is_empty() {
+        eprintln!("{} Configuration is valid", style("✓").green().bold());
+        return;
+    }
+    for problem in &problems {
+        eprintln!("{} {problem}", style("✗").red().bold());
+    }
+    std::process::exit(1);
+}
+
+/// Where to reach the server, with a QR code when it is reachable over the
+/// LAN.
+fn print_banner(config: &Config) {
+    let port = config.port;
+    let scheme = if config.tls() { "https" } else { "http" };
+    let http_url = |ip: IpAddr| format!("{scheme}://{}", SocketAddr::new(ip, port));
+    let (local, network) = match &config.listen {
+        Listen::Ip(ip) if ip.is_unspecified() => (
+            Some(format!("{scheme}://localhost:{port}")),
+            local_ip().ok().map(http_url),
+        ),
+        Listen::Ip(ip) if ip.is_loopback() => (Some(http_url(*ip)), None),
+        Listen::Ip(ip) => (None, Some(http_url(*ip))),
+        #[cfg(unix)]
+        listen @ Listen::Unix(_) => (Some(listen.to_string()), None),
+    };
+
+    info!("");
+    if let Some(local) = local {
+        info!(" {} {} ", style("Local").green().bold(), local);
+    }
+    if let Some(mut url) = network {
         info!(" {} {}", style("Network").green().bold(), url);
 
+        // Let whoever scans the code in without typing the token
+        if let Some(token) = &config.auth.auth_token {
+            url = format!(
+                "{url}/?token={}",
+                utf8_percent_encode(token, NON_ALPHANUMERIC)
+            );
+        }
+
         let qrcode = QrCode::new(url.clone()).unwrap();
         let qrcode = qrcode
             .render::<unicode::Dense1x2>()
@@ -110,5 +267,21 @@ async fn main() {
         "Press {} to stop the server",
         style("Ctrl + C").green().bold()
     );
-    axum::serve(listener, app).await.unwrap();
+}
+
+/// Any origin may read responses unless `origins` are listed, and only those
+/// may then send cookies along.
+fn cors_layer(origins: &[String]) -> CorsLayer {
+    if origins.is_empty() {
+        return CorsLayer::new().allow_origin(Any);
+    }
+
+    let origins = origins.iter().map(|origin| {
+        HeaderValue::try_from(origin.as_str()).expect("CORS origins are checked in Config::check")
+    });
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+        .allow_credentials(true)
 }
@@ -1,16 +1,156 @@
-use axum::{Router, http::StatusCode};
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    Router,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+};
+use percent_encoding::percent_decode_str;
+use post_archiver::utils::DATABASE_NAME;
 use tower_http::services::ServeDir;
 use tracing::info;
 
-use crate::config::Config;
+use crate::{
+    api::{search::SEARCH_DATABASE_NAME, viewer::VIEWER_DATABASE_NAME},
+    config::Archive,
+    tls::{SELF_SIGNED_CERT, SELF_SIGNED_KEY},
+};
+
+/// Files of the archiver and the viewer that may sit among the post files
+/// but must never be served, wherever they are.
+const PRIVATE_FILES: &[&str] = &[
+    DATABASE_NAME,
+    VIEWER_DATABASE_NAME,
+    SEARCH_DATABASE_NAME,
+    SELF_SIGNED_CERT,
+    SELF_SIGNED_KEY,
+];
+/// Files SQLite keeps next to a database while it is in use.
+const DATABASE_SUFFIXES: &[&str] = &["", "-wal", "-shm", "-journal"];
 
-pub fn get_resource_router(config: &Config) -> Router {
+pub fn get_resource_router(archive: &Archive) -> Router {
     let router = Router::new();
-    if config.public.resource_url.is_some() {
+    if archive.public.resource_url.is_some() {
         info!("Resource URL is set, disabling resource router");
         return router.fallback(|| async { StatusCode::FORBIDDEN });
     }
 
-    let serve_dir = ServeDir::new(&config.path);
-    Router::new().fallback_service(serve_dir)
+    let serve_dir = ServeDir::new(&archive.path);
+    let data_dir = Arc::new(data_dir_within(&archive.path, &archive.data_dir));
+    Router::new()
+        .fallback_service(serve_dir)
+        .layer(middleware::from_fn_with_state(data_dir, only_post_files))
+}
+
+/// Where `data_dir` is inside the archive at `path`, if it is somewhere
+/// below it rather than the archive folder itself or elsewhere.
+fn data_dir_within(path: &Path, data_dir: &Path) -> Option<PathBuf> {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let data_dir = fs::canonicalize(data_dir).unwrap_or_else(|_| data_dir.to_path_buf());
+    data_dir
+        .strip_prefix(path)
+        .ok()
+        .filter(|within| within.components().next().is_some())
+        .map(Path::to_path_buf)
+}
+
+async fn only_post_files(
+    State(data_dir): State<Arc<Option<PathBuf>>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let path = percent_decode_str(req.uri().path()).decode_utf8_lossy();
+    if !is_post_file(&path, data_dir.as_deref()) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    next.run(req).await
+}
+
+/// Post files live in folders, while the top level holds the archive and
+/// viewer databases, the TLS key and such. Those are refused by name
+/// wherever they are, as is anything in the viewer's `data_dir`.
+fn is_post_file(path: &str, data_dir: Option<&Path>) -> bool {
+    let mut normal = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => normal.push(name),
+            Component::RootDir | Component::CurDir => {}
+            _ => return false,
+        }
+    }
+
+    let private = normal.file_name().is_some_and(|name| {
+        let name = name.to_string_lossy();
+        PRIVATE_FILES.iter().any(|file| {
+            DATABASE_SUFFIXES
+                .iter()
+                .any(|suffix| name == format!("{file}{suffix}"))
+        })
+    });
+    let in_data_dir = data_dir.is_some_and(|dir| normal.starts_with(dir));
+
+    normal.components().count() >= 2 && !private && !in_data_dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn post_files_are_served() {
+        assert!(is_post_file("/1/2/image.png", None));
+        assert!(is_post_file("/1/2/image name.png", None));
+        assert!(is_post_file("/1/./2/image.png", None));
+    }
+
+    #[test]
+    fn top_level_files_are_refused() {
+        assert!(!is_post_file("/", None));
+        assert!(!is_post_file("/post-archiver.db", None));
+        assert!(!is_post_file("/notes.txt", None));
+    }
+
+    #[test]
+    fn private_files_are_refused_anywhere() {
+        for name in [
+            "post-archiver.db",
+            "post-archiver.db-wal",
+            "post-archiver-viewer.db-shm",
+            "post-archiver-viewer.key",
+            "post-archiver-viewer.crt",
+        ] {
+            assert!(!is_post_file(&format!("/1/2/{name}"), None), "{name}");
+        }
+    }
+
+    #[test]
+    fn escaping_paths_are_refused() {
+        assert!(!is_post_file("/1/../post-archiver.db", None));
+        assert!(!is_post_file("/1/2/../../x", None));
+    }
+
+    #[test]
+    fn data_dir_is_refused() {
+        let data_dir = Path::new("viewer/data");
+        assert!(!is_post_file("/viewer/data/accounts.json", Some(data_dir)));
+        assert!(!is_post_file("/viewer/data/more/file", Some(data_dir)));
+        assert!(is_post_file("/viewer/other/file", Some(data_dir)));
+    }
+
+    #[test]
+    fn data_dir_within_archive() {
+        let archive = Path::new("/srv/archive");
+        assert_eq!(
+            data_dir_within(archive, Path::new("/srv/archive/viewer")),
+            Some(PathBuf::from("viewer"))
+        );
+        assert_eq!(data_dir_within(archive, archive), None);
+        assert_eq!(data_dir_within(archive, Path::new("/var/lib/viewer")), None);
+    }
 }
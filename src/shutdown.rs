@@ -0,0 +1,175 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// Stops the server on SIGINT or SIGTERM, letting requests that are still
+/// running finish within the drain timeout.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    triggered: watch::Sender<bool>,
+    in_flight: Arc<AtomicUsize>,
+    timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            triggered: watch::channel(false).0,
+            in_flight: Arc::default(),
+            timeout,
+        }
+    }
+
+    /// Resolves once shutdown has begun.
+    pub fn triggered(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut rx = self.triggered.subscribe();
+        async move {
+            let _ = rx.wait_for(|&triggered| triggered).await;
+        }
+    }
+
+    /// Receiver that flips to `true` once shutdown has begun.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.triggered.subscribe()
+    }
+
+    /// Requests whose response has not been fully sent yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Drive `server`, which should stop accepting connections once
+    /// [`Shutdown::triggered`] resolves, until it finishes or the drain
+    /// timeout runs out after a signal.
+    pub async fn run(&self, server: impl Future<Output = io::Result<()>>) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            info!(
+                "Shutting down, waiting up to {}s for {} running requests",
+                this.timeout.as_secs(),
+                this.in_flight()
+            );
+            this.triggered.send_replace(true);
+        });
+
+        let drained = async {
+            self.triggered().await;
+            tokio::time::sleep(self.timeout).await;
+        };
+
+        tokio::select! {
+            result = server => match result {
+                Ok(()) => info!("Server stopped"),
+                Err(err) => error!("Server failed: {err}"),
+            },
+            _ = drained => warn!(
+                "Gave up waiting, dropping {} running requests",
+                self.in_flight()
+            ),
+        }
+    }
+}
+
+async fn wait_for_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl + C: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                error!("Failed to listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Count the request as in flight until its response body is done, so
+/// long downloads are included.
+pub async fn track_in_flight(
+    State(shutdown): State<Shutdown>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let guard = InFlightGuard::new(&shutdown.in_flight);
+    next.run(req).await.map(|body| {
+        Body::new(TrackedBody {
+            body,
+            _guard: guard,
+        })
+    })
+}
+
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl InFlightGuard {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count.clone())
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct TrackedBody {
+    body: Body,
+    _guard: InFlightGuard,
+}
+
+impl HttpBody for TrackedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+use axum::{
+    Router,
+    http::{HeaderMap, Uri, header::HOST, uri::Authority},
+    response::Redirect,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use local_ip_address::local_ip;
+use tracing::{error, info};
+
+use crate::{
+    config::{Config, Listen},
+    shutdown::Shutdown,
+};
+
+/// Self-signed certificate and key, kept in the viewer's data directory.
+pub const SELF_SIGNED_CERT: &str = "post-archiver-viewer.crt";
+pub const SELF_SIGNED_KEY: &str = "post-archiver-viewer.key";
+
+/// TLS settings from `--tls-cert`/`--tls-key`, or the self-signed
+/// certificate, created on first use.
+pub async fn rustls_config(config: &Config) -> Result<RustlsConfig, Box<dyn Error>> {
+    // Only ring is built in, so there is exactly one provider to pick
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    if let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) {
+        return Ok(RustlsConfig::from_pem_file(cert, key).await?);
+    }
+
+    let cert = config.data_dir().join(SELF_SIGNED_CERT);
+    let key = config.data_dir().join(SELF_SIGNED_KEY);
+    if !cert.exists() || !key.exists() {
+        generate_self_signed(config, &cert, &key)?;
+    }
+    Ok(RustlsConfig::from_pem_file(cert, key).await?)
+}
+
+/// Certificate for every name the server is likely reached by. Delete the
+/// files to get a new one, e.g. after the LAN address changed.
+fn generate_self_signed(config: &Config, cert: &Path, key: &Path) -> Result<(), Box<dyn Error>> {
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    if let Ok(ip) = local_ip() {
+        names.push(ip.to_string());
+    }
+    if let Listen::Ip(ip) = &config.listen
+        && !ip.is_unspecified()
+        && !names.contains(&ip.to_string())
+    {
+        names.push(ip.to_string());
+    }
+
+    let certified = rcgen::generate_simple_self_signed(names.clone())?;
+    // A key left over without its certificate is replaced
+    if key.exists() {
+        fs::remove_file(key)?;
+    }
+    create_private(key)?.write_all(certified.key_pair.serialize_pem().as_bytes())?;
+    fs::write(cert, certified.cert.pem())?;
+
+    info!(
+        "Generated a self-signed certificate for {} at {}",
+        names.join(", "),
+        cert.display()
+    );
+    Ok(())
+}
+
+/// Create `path` readable by the owner only, from the start rather than
+/// narrowing it down after the secret is written.
+fn create_private(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Listen for plain HTTP on `port`, sending everyone to the same page over
+/// HTTPS on `https_port`, until `shutdown` is triggered.
+pub async fn redirect_http(ip: IpAddr, port: u16, https_port: u16, shutdown: Shutdown) {
+    let addr = SocketAddr::new(ip, port);
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Failed to listen for HTTP redirects on {addr}: {err}");
+            return;
+        }
+    };
+    info!("Redirecting HTTP on port {port} to HTTPS");
+
+    let redirect = Router::new().fallback(async move |headers: HeaderMap, uri: Uri| {
+        let host = headers
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok());
+        let host = host.as_ref().map_or("localhost", Authority::host);
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+
+        let url = match https_port {
+            443 => format!("https://{host}{path}"),
+            port => format!("https://{host}:{port}{path}"),
+        };
+        Redirect::permanent(&url)
+    });
+    let server = axum::serve(listener, redirect).with_graceful_shutdown(shutdown.triggered());
+    if let Err(err) = server.await {
+        error!("HTTP redirect listener failed: {err}");
+    }
+}